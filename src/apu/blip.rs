@@ -0,0 +1,138 @@
+use std::f64::consts::PI;
+
+/// Number of output samples each amplitude change is spread over
+const KERNEL_WIDTH: usize = 16;
+/// Number of sub-sample positions the kernel is precomputed for
+const PHASES: usize = 64;
+/// Cutoff of the kernel relative to the output Nyquist frequency, leaving some
+/// room for the transition band
+const CUTOFF: f64 = 0.9;
+
+/// A band-limited resampler in the style of blargg's Blip_Buffer.
+///
+/// The APU's output is a step function that changes at most once per CPU
+/// cycle. Instead of sampling it (which aliases badly), each change is added
+/// to the output as a band-limited step: the difference between the old and
+/// new amplitude, multiplied by a windowed sinc placed at the exact
+/// sub-sample time of the change. Integrating the result gives the output
+/// samples.
+pub struct BlipBuffer {
+    /// Output samples per input clock
+    factor: f64,
+    /// Output position of clock 0 of the current frame
+    offset: f64,
+    /// Impulse responses for each sub-sample phase
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    /// Accumulated impulses, to be integrated
+    buf: Vec<f32>,
+    integrator: f32,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> BlipBuffer {
+        let mut kernel = vec![[0.0; KERNEL_WIDTH]; PHASES];
+        for (phase, impulse) in kernel.iter_mut().enumerate() {
+            let frac = phase as f64 / PHASES as f64;
+            let mut sum = 0.0;
+            let mut taps = [0.0; KERNEL_WIDTH];
+            for (k, tap) in taps.iter_mut().enumerate() {
+                // Distance from the step to this output sample
+                let t = k as f64 - (KERNEL_WIDTH / 2) as f64 + 1.0 - frac;
+                let x = PI * CUTOFF * t;
+                let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+                // Blackman window over the kernel width
+                let w = 2.0 * PI * (t + (KERNEL_WIDTH / 2) as f64) / KERNEL_WIDTH as f64;
+                let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+                *tap = sinc * window;
+                sum += *tap;
+            }
+            // Normalize so that each step has a gain of exactly 1
+            for (k, tap) in taps.iter().enumerate() {
+                impulse[k] = (tap / sum) as f32;
+            }
+        }
+
+        let mut blip = BlipBuffer {
+            factor: 0.0,
+            offset: 0.0,
+            kernel: kernel,
+            buf: Vec::new(),
+            integrator: 0.0,
+        };
+        blip.set_rates(clock_rate, sample_rate);
+        blip
+    }
+
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.factor = sample_rate / clock_rate;
+    }
+
+    /// Adds an amplitude change of `delta` at `time` clocks into the current
+    /// frame.
+    pub fn add_delta(&mut self, time: usize, delta: f32) {
+        let pos = self.offset + time as f64 * self.factor;
+        let index = pos as usize;
+        let phase = ((pos - index as f64) * PHASES as f64) as usize;
+        if self.buf.len() < index + KERNEL_WIDTH {
+            self.buf.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        let impulse = &self.kernel[phase.min(PHASES - 1)];
+        for (k, tap) in impulse.iter().enumerate() {
+            self.buf[index + k] += delta * tap;
+        }
+    }
+
+    /// Ends the current frame after `clocks` clocks and appends the samples
+    /// that are now complete to `out`. The next frame starts at clock 0.
+    pub fn end_frame(&mut self, clocks: usize, out: &mut Vec<f32>) {
+        let end = self.offset + clocks as f64 * self.factor;
+        let count = end as usize;
+        if self.buf.len() < count + KERNEL_WIDTH {
+            self.buf.resize(count + KERNEL_WIDTH, 0.0);
+        }
+        for val in self.buf.drain(..count) {
+            self.integrator += val;
+            out.push(self.integrator);
+        }
+        self.offset = end - count as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::BlipBuffer;
+
+    const CLOCK_RATE: f64 = 1_789_773.0;
+    const SAMPLE_RATE: f64 = 48000.0;
+    const FRAME_CLOCKS: usize = 29781;
+
+    #[test]
+    fn dc_level() {
+        let mut blip = BlipBuffer::new(CLOCK_RATE, SAMPLE_RATE);
+        blip.add_delta(100, 0.5);
+        let mut out = Vec::new();
+        for _ in 0..3 {
+            out.clear();
+            blip.end_frame(FRAME_CLOCKS, &mut out);
+        }
+        for &sample in out.iter() {
+            assert!((sample - 0.5).abs() < 1e-4, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn samples_per_frame() {
+        let mut blip = BlipBuffer::new(CLOCK_RATE, SAMPLE_RATE);
+        let per_frame = FRAME_CLOCKS as f64 * SAMPLE_RATE / CLOCK_RATE;
+        let mut out = Vec::new();
+        for frame in 1..=60 {
+            let start = out.len();
+            blip.end_frame(FRAME_CLOCKS, &mut out);
+            let count = (out.len() - start) as f64;
+            assert!((count - per_frame).abs() < 1.0);
+            // The fractions add up instead of being dropped
+            assert!((out.len() as f64 - frame as f64 * per_frame).abs() < 1.0);
+        }
+    }
+}
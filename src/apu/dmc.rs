@@ -0,0 +1,205 @@
+/// Timer periods in CPU cycles for NTSC systems
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// The delta modulation channel, $4010-$4013, which plays 1-bit delta-encoded
+/// samples read from CPU memory.
+///
+/// The channel can't access memory by itself: when its sample buffer is empty
+/// it requests a byte through `dma_request`, which the console fetches
+/// (stalling the CPU) and hands back through `dma_fill`.
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    /// 7-bit output level
+    level: u8,
+
+    // Memory reader
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // Output unit
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+
+    /// Interrupt flag, set when a non-looping sample ends
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            timer_period: RATE_TABLE[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    /// Handles a write to one of the channel's four registers.
+    pub fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            // IL-- RRRR: IRQ enabled, loop, rate
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = val & 0x40 != 0;
+                self.timer_period = RATE_TABLE[(val & 0x0F) as usize];
+            }
+            // -DDD DDDD: direct load of the output level
+            1 => self.level = val & 0x7F,
+            // AAAA AAAA: sample address %11AAAAAA.AA000000
+            2 => self.sample_address = 0xC000 | (u16::from(val) << 6),
+            // LLLL LLLL: sample length %LLLL.LLLL0001
+            _ => self.sample_length = (u16::from(val) << 4) | 1,
+        }
+    }
+
+    /// Handles the DMC bit of a $4015 write, which also acknowledges the IRQ.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    /// Whether there are sample bytes left to fetch
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// The address of the next sample byte, if the memory reader needs one.
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    /// Fills the sample buffer with the byte requested by `dma_request`.
+    pub fn dma_fill(&mut self, val: u8) {
+        self.sample_buffer = Some(val);
+        // The address wraps around to $8000, not $0000
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Moves the output level up or down by 2 according to the next bit of
+    /// the sample, then starts a new output cycle after 8 bits.
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(val) => {
+                    self.silence = false;
+                    self.shift_register = val;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// The current output level (0-127)
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Dmc;
+
+    #[test]
+    fn irq_at_end_of_sample() {
+        let mut dmc = Dmc::new();
+        dmc.write_register(0, 0x80);
+        // 17 bytes
+        dmc.write_register(3, 0x01);
+        dmc.set_enabled(true);
+        for i in 0..17 {
+            assert!(!dmc.irq);
+            assert_eq!(dmc.dma_request(), Some(0xC000 + i));
+            dmc.dma_fill(0);
+            // Let the output unit empty the sample buffer
+            for _ in 0..8 * 428 {
+                dmc.clock_timer();
+            }
+        }
+        assert!(dmc.irq);
+        assert_eq!(dmc.dma_request(), None);
+        // Writing $4015 acknowledges the IRQ
+        dmc.set_enabled(false);
+        assert!(!dmc.irq);
+    }
+
+    #[test]
+    fn no_irq_when_looping() {
+        let mut dmc = Dmc::new();
+        dmc.write_register(0, 0xC0);
+        dmc.write_register(3, 0x00);
+        dmc.set_enabled(true);
+        dmc.dma_fill(0);
+        assert!(!dmc.irq);
+        assert!(dmc.active());
+    }
+}
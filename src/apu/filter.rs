@@ -0,0 +1,99 @@
+use std::f32::consts::PI;
+
+/// A first-order IIR filter, run at the output sample rate.
+///
+/// The NES's audio path goes through two high-pass filters (90 Hz and
+/// 440 Hz) and a low-pass filter (14 kHz), which `Filter::nes_chain` builds.
+pub struct Filter {
+    kind: FilterKind,
+    cutoff: f32,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+#[derive(Clone, Copy)]
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+impl Filter {
+    pub fn high_pass(cutoff: f32, sample_rate: f32) -> Filter {
+        Filter::new(FilterKind::HighPass, cutoff, sample_rate)
+    }
+
+    pub fn low_pass(cutoff: f32, sample_rate: f32) -> Filter {
+        Filter::new(FilterKind::LowPass, cutoff, sample_rate)
+    }
+
+    /// The filters between the APU and the audio output of a NES
+    pub fn nes_chain(sample_rate: f32) -> [Filter; 3] {
+        [
+            Filter::high_pass(90.0, sample_rate),
+            Filter::high_pass(440.0, sample_rate),
+            Filter::low_pass(14000.0, sample_rate),
+        ]
+    }
+
+    fn new(kind: FilterKind, cutoff: f32, sample_rate: f32) -> Filter {
+        let mut filter = Filter {
+            kind: kind,
+            cutoff: cutoff,
+            alpha: 0.0,
+            prev_in: 0.0,
+            prev_out: 0.0,
+        };
+        filter.set_sample_rate(sample_rate);
+        filter
+    }
+
+    /// Recomputes the coefficient for a new sample rate, keeping the state.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        let rc = 1.0 / (2.0 * PI * self.cutoff);
+        let dt = 1.0 / sample_rate;
+        self.alpha = match self.kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.prev_out + input - self.prev_in),
+            FilterKind::LowPass => self.prev_out + self.alpha * (input - self.prev_out),
+        };
+        self.prev_in = input;
+        self.prev_out = output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Filter;
+
+    #[test]
+    fn high_pass_decays_step() {
+        let mut filter = Filter::high_pass(90.0, 48000.0);
+        assert!(filter.process(1.0) > 0.98);
+        let mut output = 1.0;
+        for _ in 0..48000 {
+            let next = filter.process(1.0);
+            assert!(next <= output);
+            output = next;
+        }
+        assert!(output.abs() < 1e-3, "{}", output);
+    }
+
+    #[test]
+    fn low_pass_passes_dc() {
+        let mut filter = Filter::low_pass(14000.0, 48000.0);
+        let mut output = 0.0;
+        for _ in 0..100 {
+            output = filter.process(1.0);
+        }
+        assert!((output - 1.0).abs() < 1e-3, "{}", output);
+    }
+}
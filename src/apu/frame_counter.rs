@@ -0,0 +1,180 @@
+/// A clock the frame counter sends to the channels
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FrameClock {
+    None,
+    /// Clocks envelopes and the triangle's linear counter
+    Quarter,
+    /// Clocks length counters and sweep units, in addition to a quarter frame
+    Half,
+}
+
+/// The frame counter ($4017), which clocks the channels' envelopes, sweeps
+/// and length counters about four times per frame and may raise an IRQ.
+///
+/// Timings are in CPU cycles for NTSC systems, counted from when a $4017
+/// write takes effect.
+///
+/// ```text
+/// mode 0 (4-step)          mode 1 (5-step)
+///  7457: quarter            7457: quarter
+/// 14913: half              14913: half
+/// 22371: quarter           22371: quarter
+/// 29828: IRQ               29829: -
+/// 29829: half, IRQ         37281: half
+/// 29830: IRQ, reset        37282: reset
+/// ```
+pub struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    /// Frame interrupt flag
+    pub irq: bool,
+    cycle: u16,
+    /// Cycles until the last $4017 write takes effect
+    write_delay: usize,
+    /// Mode written to $4017, applied when the write takes effect
+    pending_five_step: bool,
+}
+
+impl FrameCounter {
+    pub fn new() -> FrameCounter {
+        FrameCounter {
+            five_step: false,
+            irq_inhibit: false,
+            irq: false,
+            cycle: 0,
+            write_delay: 0,
+            pending_five_step: false,
+        }
+    }
+
+    /// Handles a write to $4017 (MI-- ----: mode, IRQ inhibit) made `lag`
+    /// cycles from now.
+    ///
+    /// Setting the inhibit flag clears the interrupt flag immediately, but the
+    /// mode only changes and the timer is only reset 3 or 4 CPU cycles after
+    /// the write, depending on whether it happened on an even or an odd CPU
+    /// cycle.
+    pub fn write(&mut self, val: u8, odd_cycle: bool, lag: usize) {
+        self.pending_five_step = val & 0x80 != 0;
+        self.irq_inhibit = val & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq = false;
+        }
+        self.write_delay = lag + if odd_cycle { 4 } else { 3 };
+    }
+
+    /// Advances the frame counter by one CPU cycle.
+    pub fn step(&mut self) -> FrameClock {
+        if self.write_delay > 0 {
+            self.write_delay -= 1;
+            if self.write_delay == 0 {
+                self.five_step = self.pending_five_step;
+                self.cycle = 0;
+                // Entering 5-step mode clocks a half frame immediately
+                if self.five_step {
+                    return FrameClock::Half;
+                }
+                return FrameClock::None;
+            }
+        }
+
+        self.cycle += 1;
+        match (self.cycle, self.five_step) {
+            (7457, _) | (22371, _) => FrameClock::Quarter,
+            (14913, _) => FrameClock::Half,
+            (29828, false) => {
+                self.set_irq();
+                FrameClock::None
+            }
+            (29829, false) => {
+                self.set_irq();
+                FrameClock::Half
+            }
+            (29830, false) => {
+                self.set_irq();
+                self.cycle = 0;
+                FrameClock::None
+            }
+            (37281, true) => FrameClock::Half,
+            (37282, true) => {
+                self.cycle = 0;
+                FrameClock::None
+            }
+            _ => FrameClock::None,
+        }
+    }
+
+    fn set_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{FrameClock, FrameCounter};
+
+    /// Writes $4017 on an even cycle and steps until the write takes effect.
+    fn reset(val: u8) -> FrameCounter {
+        let mut frame_counter = FrameCounter::new();
+        frame_counter.write(val, false, 0);
+        for _ in 0..3 {
+            frame_counter.step();
+        }
+        frame_counter
+    }
+
+    #[test]
+    fn irq_in_four_step_mode() {
+        let mut frame_counter = reset(0x00);
+        for _ in 1..29828 {
+            frame_counter.step();
+        }
+        assert!(!frame_counter.irq);
+        assert!(frame_counter.step() == FrameClock::None);
+        assert!(frame_counter.irq);
+        assert!(frame_counter.step() == FrameClock::Half);
+        // Setting the inhibit flag acknowledges the interrupt
+        frame_counter.write(0x40, false, 0);
+        assert!(!frame_counter.irq);
+    }
+
+    #[test]
+    fn no_irq_when_inhibited() {
+        let mut frame_counter = reset(0x40);
+        for _ in 0..2 * 29830 {
+            frame_counter.step();
+        }
+        assert!(!frame_counter.irq);
+    }
+
+    #[test]
+    fn no_irq_in_five_step_mode() {
+        let mut frame_counter = reset(0x80);
+        for _ in 0..2 * 37282 {
+            frame_counter.step();
+        }
+        assert!(!frame_counter.irq);
+    }
+
+    #[test]
+    fn mode_changes_when_write_takes_effect() {
+        let mut frame_counter = reset(0x00);
+        for _ in 1..29827 {
+            frame_counter.step();
+        }
+        // Switching to 5-step mode on an odd cycle takes 4 cycles, during
+        // which the 4-step sequence still raises the interrupt.
+        frame_counter.write(0x80, true, 0);
+        assert!(frame_counter.step() == FrameClock::None);
+        assert!(frame_counter.step() == FrameClock::None);
+        assert!(frame_counter.step() == FrameClock::Half);
+        assert!(frame_counter.irq);
+        assert!(!frame_counter.five_step);
+        // Entering 5-step mode clocks a half frame
+        assert!(frame_counter.step() == FrameClock::Half);
+        assert!(frame_counter.five_step);
+    }
+}
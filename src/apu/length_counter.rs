@@ -0,0 +1,63 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Length counter lengths, indexed by the 5-bit value written to a channel's
+/// length register.
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// The length counter, which silences a channel after a programmed duration.
+/// It is clocked by the frame counter on every half frame.
+#[derive(Default)]
+pub struct LengthCounter {
+    /// Cleared through $4015, which also forces the counter to 0
+    enabled: bool,
+    /// Halt flag: stops the counter from being clocked
+    halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    /// Reloads the counter from the length table, if the channel is enabled.
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    /// Whether the counter has not reached 0 yet, i.e. the channel may play
+    pub fn active(&self) -> bool {
+        self.value > 0
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.halt);
+        state.write_u8(self.value);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.halt = state.read_bool()?;
+        self.value = state.read_u8()?;
+        Ok(())
+    }
+}
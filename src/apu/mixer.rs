@@ -0,0 +1,60 @@
+/// Combines the channel outputs the way the NES's resistor network does,
+/// which is not linear: the louder the other channels, the quieter each one.
+///
+/// Uses the lookup table approximation from nesdev:
+///
+/// ```text
+/// pulse_out = 95.52 / (8128.0 / (pulse1 + pulse2) + 100)
+/// tnd_out = 163.67 / (24329.0 / (3 * triangle + 2 * noise + dmc) + 100)
+/// ```
+pub struct Mixer {
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+}
+
+impl Mixer {
+    pub fn new() -> Mixer {
+        let mut pulse_table = [0.0; 31];
+        for (n, out) in pulse_table.iter_mut().enumerate().skip(1) {
+            *out = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, out) in tnd_table.iter_mut().enumerate().skip(1) {
+            *out = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Mixer {
+            pulse_table: pulse_table,
+            tnd_table: tnd_table,
+        }
+    }
+
+    /// Mixes the channel levels (0-15 for the tone channels, 0-127 for the
+    /// DMC) into an amplitude between 0.0 and 1.0.
+    pub fn mix(&self, pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = (pulse1 + pulse2) as usize;
+        let tnd = 3 * triangle as usize + 2 * noise as usize + dmc as usize;
+        self.pulse_table[pulse] + self.tnd_table[tnd]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Mixer;
+
+    #[test]
+    fn nesdev_formulas() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0), 0.0);
+
+        let pulse_out = 95.52 / (8128.0 / 30.0 + 100.0);
+        assert!((mixer.mix(15, 15, 0, 0, 0) - pulse_out).abs() < 1e-6);
+
+        let tnd_out = 163.67 / (24329.0 / (3.0 * 15.0 + 2.0 * 15.0 + 127.0) + 100.0);
+        assert!((mixer.mix(0, 0, 15, 15, 127) - tnd_out).abs() < 1e-6);
+
+        let max = mixer.mix(15, 15, 15, 15, 127);
+        assert!((max - (pulse_out + tnd_out)).abs() < 1e-6);
+        assert!(max < 1.0);
+    }
+}
@@ -0,0 +1,263 @@
+mod blip;
+mod dmc;
+mod envelope;
+mod filter;
+mod frame_counter;
+mod length_counter;
+mod mixer;
+mod noise;
+mod output;
+pub(crate) mod pulse;
+mod triangle;
+
+use self::dmc::Dmc;
+use self::frame_counter::{FrameClock, FrameCounter};
+use self::mixer::Mixer;
+use self::noise::Noise;
+use self::output::AudioOutput;
+use self::pulse::Pulse;
+use self::triangle::Triangle;
+
+/// CPU (and APU) clock rate of NTSC systems, in Hz
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+
+/// Output sample rate used until `Apu::set_sample_rate` is called
+pub const DEFAULT_SAMPLE_RATE: f64 = 44_100.0;
+
+/// Number of sound channels
+pub const CHANNELS: usize = 5;
+
+/// Names of the sound channels, as used for recordings
+pub const CHANNEL_NAMES: [&str; CHANNELS] = ["pulse1", "pulse2", "triangle", "noise", "dmc"];
+
+/// The audio processing unit, mapped at $4000-$4017.
+///
+/// `step` is called once per CPU cycle. The pulse timers are clocked every
+/// other CPU cycle (once per APU cycle), the triangle, noise and DMC timers
+/// every CPU cycle.
+///
+/// The mixed output (and optionally the output of each channel) is resampled
+/// to the output sample rate as it is generated, and collected with
+/// `end_frame`.
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    frame_counter: FrameCounter,
+    /// Whether the current CPU cycle is the second half of an APU cycle
+    odd_cycle: bool,
+
+    mixer: Mixer,
+    /// Output level of the cartridge's expansion audio, mixed in as is
+    expansion_audio: f32,
+    sample_rate: f64,
+    output: AudioOutput,
+    /// Outputs of the pulse 1, pulse 2, triangle, noise and DMC channels on
+    /// their own, if enabled
+    channel_outputs: Vec<AudioOutput>,
+    /// CPU cycles since the last `end_frame`
+    frame_cycle: usize,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            pulse1: Pulse::new(1),
+            pulse2: Pulse::new(2),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            odd_cycle: false,
+            mixer: Mixer::new(),
+            expansion_audio: 0.0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            output: AudioOutput::new(DEFAULT_SAMPLE_RATE),
+            channel_outputs: Vec::new(),
+            frame_cycle: 0,
+        }
+    }
+
+    /// Sets the rate of the samples returned by `end_frame`. It may be
+    /// adjusted slightly between frames to control the latency of the audio
+    /// output.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.output.set_sample_rate(sample_rate);
+        for output in self.channel_outputs.iter_mut() {
+            output.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Enables or disables the separate output of each channel, e.g. for
+    /// recording them individually.
+    pub fn set_channel_outputs(&mut self, enabled: bool) {
+        self.channel_outputs.clear();
+        if enabled {
+            for _ in 0..CHANNELS {
+                self.channel_outputs
+                    .push(AudioOutput::new(self.sample_rate));
+            }
+        }
+    }
+
+    /// Silences all channels, as on reset.
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+    }
+
+    /// Handles a write to $4000-$4013 or $4015.
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4000...0x4003 => self.pulse1.write_register(addr - 0x4000, val),
+            0x4004...0x4007 => self.pulse2.write_register(addr - 0x4004, val),
+            0x4008...0x400B => self.triangle.write_register(addr - 0x4008, val),
+            0x400C...0x400F => self.noise.write_register(addr - 0x400C, val),
+            0x4010...0x4013 => self.dmc.write_register(addr - 0x4010, val),
+            // ---D NT21: enable DMC, noise, triangle, pulse 2, pulse 1
+            0x4015 => {
+                self.pulse1.length_counter.set_enabled(val & 0x01 != 0);
+                self.pulse2.length_counter.set_enabled(val & 0x02 != 0);
+                self.triangle.length_counter.set_enabled(val & 0x04 != 0);
+                self.noise.length_counter.set_enabled(val & 0x08 != 0);
+                self.dmc.set_enabled(val & 0x10 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    /// Reads $4015: IF-D NT21, DMC and frame interrupts and whether each
+    /// channel's length counter is non-zero. Bit 5 is open bus.
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        // Reading acknowledges the frame interrupt
+        self.frame_counter.irq = false;
+        status
+    }
+
+    /// Returns the value of $4015 without side effects.
+    pub fn peek_status(&self) -> u8 {
+        self.pulse1.length_counter.active() as u8
+            | (self.pulse2.length_counter.active() as u8) << 1
+            | (self.triangle.length_counter.active() as u8) << 2
+            | (self.noise.length_counter.active() as u8) << 3
+            | (self.dmc.active() as u8) << 4
+            | (self.frame_counter.irq as u8) << 6
+            | (self.dmc.irq as u8) << 7
+    }
+
+    /// Handles a write to $4017, which happened on the CPU cycle `lag` cycles
+    /// ahead of the APU.
+    pub fn write_frame_counter(&mut self, val: u8, odd_cycle: bool, lag: usize) {
+        self.frame_counter.write(val, odd_cycle, lag);
+    }
+
+    /// Sets the level of the cartridge's expansion audio for the next cycles.
+    pub fn set_expansion_audio(&mut self, level: f32) {
+        self.expansion_audio = level;
+    }
+
+    /// Clocks envelopes and the triangle's linear counter.
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    /// Clocks length counters and sweep units.
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    /// Advances the APU by one CPU cycle.
+    pub fn step(&mut self) {
+        match self.frame_counter.step() {
+            FrameClock::Quarter => self.clock_quarter_frame(),
+            FrameClock::Half => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FrameClock::None => {}
+        }
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.odd_cycle = !self.odd_cycle;
+
+        let amplitude = self.mixer.mix(
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ) + self.expansion_audio;
+        self.output.update(self.frame_cycle, amplitude);
+
+        if !self.channel_outputs.is_empty() {
+            let mixer = &self.mixer;
+            let levels = [
+                mixer.mix(self.pulse1.output(), 0, 0, 0, 0),
+                mixer.mix(0, self.pulse2.output(), 0, 0, 0),
+                mixer.mix(0, 0, self.triangle.output(), 0, 0),
+                mixer.mix(0, 0, 0, self.noise.output(), 0),
+                mixer.mix(0, 0, 0, 0, self.dmc.output()),
+            ];
+            for (output, &level) in self.channel_outputs.iter_mut().zip(levels.iter()) {
+                output.update(self.frame_cycle, level);
+            }
+        }
+        self.frame_cycle += 1;
+    }
+
+    /// Appends the samples generated since the last call to `out`, filtered
+    /// like the NES's audio output. If channel outputs are enabled, the
+    /// samples of each channel are appended to `channels_out` in the same
+    /// order as `CHANNEL_NAMES`.
+    pub fn end_frame(&mut self, out: &mut Vec<f32>, channels_out: &mut [Vec<f32>]) {
+        self.output.end_frame(self.frame_cycle, out);
+        for (output, out) in self.channel_outputs.iter_mut().zip(channels_out) {
+            output.end_frame(self.frame_cycle, out);
+        }
+        self.frame_cycle = 0;
+    }
+
+    /// The address of the sample byte the DMC wants to fetch, if any. The
+    /// console reads it, stalls the CPU and hands it to `dmc_fill`.
+    pub fn dmc_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.dma_fill(val);
+    }
+
+    /// Level of the frame counter's interrupt output
+    pub fn frame_irq(&self) -> bool {
+        self.frame_counter.irq
+    }
+
+    /// Level of the DMC's interrupt output
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
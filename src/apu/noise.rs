@@ -0,0 +1,108 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+
+/// Timer periods in CPU cycles for NTSC systems
+const NOISE_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// The noise channel, $400C-$400F, which outputs pseudo-random bits from a
+/// 15-bit linear feedback shift register.
+pub struct Noise {
+    /// Short mode: feedback from bit 6 instead of bit 1, which produces a
+    /// 93-step metallic tone instead of 32767-step noise
+    mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+    pub length_counter: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            mode: false,
+            timer_period: NOISE_TABLE[0],
+            timer: 0,
+            // Loaded with 1 on power-up
+            shift_register: 1,
+            length_counter: LengthCounter::default(),
+            envelope: Envelope::default(),
+        }
+    }
+
+    /// Handles a write to one of the channel's four registers.
+    pub fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            // --LC VVVV: length counter halt / envelope loop, constant volume,
+            // volume / envelope period
+            0 => {
+                self.length_counter.set_halt(val & 0x20 != 0);
+                self.envelope.write_control(val);
+            }
+            1 => {}
+            // M--- PPPP: mode, period
+            2 => {
+                self.mode = val & 0x80 != 0;
+                self.timer_period = NOISE_TABLE[(val & 0x0F) as usize];
+            }
+            // llll l---: length counter load
+            _ => {
+                self.length_counter.load(val >> 3);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let other = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> other)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    /// The current output level (0-15)
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active() || self.shift_register & 1 == 1 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Noise;
+
+    #[test]
+    fn feedback_bit() {
+        let mut noise = Noise::new();
+        noise.shift_register = 0x41;
+        noise.clock_timer();
+        // Bit 0 xor bit 1
+        assert_eq!(noise.shift_register, 0x4020);
+
+        noise.write_register(2, 0x80);
+        noise.shift_register = 0x41;
+        noise.timer = 0;
+        noise.clock_timer();
+        // Bit 0 xor bit 6
+        assert_eq!(noise.shift_register, 0x0020);
+    }
+}
@@ -0,0 +1,49 @@
+use super::blip::BlipBuffer;
+use super::filter::Filter;
+use super::CPU_CLOCK_RATE;
+
+/// Turns an amplitude that changes once per CPU cycle into filtered samples
+/// at the output sample rate.
+pub struct AudioOutput {
+    blip: BlipBuffer,
+    filters: [Filter; 3],
+    /// Level on the last cycle
+    amplitude: f32,
+}
+
+impl AudioOutput {
+    pub fn new(sample_rate: f64) -> AudioOutput {
+        AudioOutput {
+            blip: BlipBuffer::new(CPU_CLOCK_RATE, sample_rate),
+            filters: Filter::nes_chain(sample_rate as f32),
+            amplitude: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.blip.set_rates(CPU_CLOCK_RATE, sample_rate);
+        for filter in self.filters.iter_mut() {
+            filter.set_sample_rate(sample_rate as f32);
+        }
+    }
+
+    /// Sets the amplitude from CPU cycle `time` of the current frame on.
+    pub fn update(&mut self, time: usize, amplitude: f32) {
+        if amplitude != self.amplitude {
+            self.blip.add_delta(time, amplitude - self.amplitude);
+            self.amplitude = amplitude;
+        }
+    }
+
+    /// Ends the current frame after `clocks` CPU cycles and appends its
+    /// samples to `out`.
+    pub fn end_frame(&mut self, clocks: usize, out: &mut Vec<f32>) {
+        let start = out.len();
+        self.blip.end_frame(clocks, out);
+        for sample in out[start..].iter_mut() {
+            for filter in self.filters.iter_mut() {
+                *sample = filter.process(*sample);
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Waveforms for the four duty cycles (12.5%, 25%, 50% and 25% negated)
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// One of the two pulse (square wave) channels, $4000-$4003 and $4004-$4007.
+///
+/// Also used for the MMC5's pulse channels, which have no sweep unit.
+pub struct Pulse {
+    /// 1 or 2. The sweep units of the two channels negate differently.
+    channel: u8,
+    /// Cleared for the MMC5's channels
+    has_sweep: bool,
+    duty: u8,
+    /// Position in the 8-step duty sequence
+    duty_pos: u8,
+    /// 11-bit timer period
+    timer_period: u16,
+    timer: u16,
+    pub length_counter: LengthCounter,
+    envelope: Envelope,
+
+    // Sweep unit
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    pub fn new(channel: u8) -> Pulse {
+        Pulse {
+            channel: channel,
+            has_sweep: true,
+            duty: 0,
+            duty_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            length_counter: LengthCounter::default(),
+            envelope: Envelope::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    /// A pulse channel without a sweep unit, as found on the MMC5. Writes to
+    /// its second register are ignored and it is never muted by the period.
+    pub fn without_sweep() -> Pulse {
+        Pulse {
+            has_sweep: false,
+            ..Pulse::new(1)
+        }
+    }
+
+    /// Handles a write to one of the channel's four registers.
+    pub fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            // DDLC VVVV: duty, length counter halt / envelope loop, constant
+            // volume, volume / envelope period
+            0 => {
+                self.duty = val >> 6;
+                self.length_counter.set_halt(val & 0x20 != 0);
+                self.envelope.write_control(val);
+            }
+            1 if !self.has_sweep => {}
+            // EPPP NSSS: sweep enabled, period, negate, shift
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 0x07;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 0x07;
+                self.sweep_reload = true;
+            }
+            // LLLL LLLL: timer low
+            2 => self.timer_period = (self.timer_period & 0x0700) | u16::from(val),
+            // llll lHHH: length counter load, timer high
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (u16::from(val & 0x07) << 8);
+                self.length_counter.load(val >> 3);
+                self.envelope.restart();
+                self.duty_pos = 0;
+            }
+        }
+    }
+
+    /// Clocked every APU cycle (every other CPU cycle).
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+        self.clock_sweep();
+    }
+
+    /// The period the sweep unit continuously computes, which it may write
+    /// back to the timer.
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            // Pulse 1 adds the ones' complement (-change - 1), pulse 2 the
+            // two's complement (-change)
+            let change = if self.channel == 1 {
+                change + 1
+            } else {
+                change
+            };
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    /// The sweep unit mutes the channel when the period is too low or the
+    /// target period overflows, even if the sweep is disabled.
+    fn sweep_muted(&self) -> bool {
+        self.has_sweep && (self.timer_period < 8 || self.sweep_target() > 0x7FF)
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0
+            && self.sweep_enabled
+            && self.sweep_shift > 0
+            && !self.sweep_muted()
+        {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    /// The current output level (0-15)
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active()
+            || self.sweep_muted()
+            || DUTY_TABLE[self.duty as usize][self.duty_pos as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    /// Saves the channel's registers and timers. The channel number and sweep
+    /// presence are fixed at construction and not saved.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_pos);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        self.length_counter.save_state(state);
+        self.envelope.save_state(state);
+        state.write_bool(self.sweep_enabled);
+        state.write_u8(self.sweep_period);
+        state.write_bool(self.sweep_negate);
+        state.write_u8(self.sweep_shift);
+        state.write_bool(self.sweep_reload);
+        state.write_u8(self.sweep_divider);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.read_u8()?;
+        self.duty_pos = state.read_u8()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.length_counter.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.sweep_enabled = state.read_bool()?;
+        self.sweep_period = state.read_u8()?;
+        self.sweep_negate = state.read_bool()?;
+        self.sweep_shift = state.read_u8()?;
+        self.sweep_reload = state.read_bool()?;
+        self.sweep_divider = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Pulse;
+
+    #[test]
+    fn sweep_negate_differs_between_channels() {
+        let mut pulse1 = Pulse::new(1);
+        let mut pulse2 = Pulse::new(2);
+        for pulse in [&mut pulse1, &mut pulse2].iter_mut() {
+            pulse.write_register(2, 0x00);
+            pulse.write_register(3, 0x01);
+            // Enabled, negate, shift 1
+            pulse.write_register(1, 0x89);
+        }
+        assert_eq!(pulse1.sweep_target(), 0x100 - 0x80 - 1);
+        assert_eq!(pulse2.sweep_target(), 0x100 - 0x80);
+    }
+
+    #[test]
+    fn sweep_mutes_on_overflow() {
+        let mut pulse = Pulse::new(1);
+        pulse.length_counter.set_enabled(true);
+        pulse.write_register(0, 0xBF);
+        pulse.write_register(2, 0x00);
+        pulse.write_register(3, 0x06);
+        pulse.write_register(1, 0x02);
+        pulse.clock_timer();
+        assert_eq!(pulse.output(), 15);
+        // $600 + $600 >> 1 > $7FF, even with the sweep disabled
+        pulse.write_register(1, 0x01);
+        assert_eq!(pulse.output(), 0);
+    }
+
+    #[test]
+    fn length_counter_load() {
+        let mut pulse = Pulse::new(1);
+        pulse.write_register(3, 0x08);
+        assert!(!pulse.length_counter.active());
+
+        pulse.length_counter.set_enabled(true);
+        // Index 1: 254
+        pulse.write_register(3, 0x08);
+        for _ in 0..253 {
+            pulse.clock_half_frame();
+        }
+        assert!(pulse.length_counter.active());
+        pulse.clock_half_frame();
+        assert!(!pulse.length_counter.active());
+
+        // Disabling through $4015 clears the counter
+        pulse.write_register(3, 0x08);
+        pulse.length_counter.set_enabled(false);
+        assert!(!pulse.length_counter.active());
+    }
+}
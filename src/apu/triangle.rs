@@ -0,0 +1,109 @@
+use super::length_counter::LengthCounter;
+
+/// The 32-step sequence of the triangle wave
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// The triangle channel, $4008-$400B.
+#[derive(Default)]
+pub struct Triangle {
+    /// Control flag (also the length counter halt flag)
+    control: bool,
+    /// 11-bit timer period
+    timer_period: u16,
+    timer: u16,
+    /// Position in the 32-step sequence
+    sequence_pos: u8,
+    pub length_counter: LengthCounter,
+
+    // Linear counter
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    /// Handles a write to one of the channel's four registers.
+    pub fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            // CRRR RRRR: control / length counter halt, linear counter reload
+            // value
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.length_counter.set_halt(self.control);
+                self.linear_reload_value = val & 0x7F;
+            }
+            1 => {}
+            // LLLL LLLL: timer low
+            2 => self.timer_period = (self.timer_period & 0x0700) | u16::from(val),
+            // llll lHHH: length counter load, timer high
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (u16::from(val & 0x07) << 8);
+                self.length_counter.load(val >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle. The sequencer only advances while both the
+    /// length counter and the linear counter are non-zero.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length_counter.active() && self.linear_counter > 0 {
+                self.sequence_pos = (self.sequence_pos + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocks the linear counter.
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    /// The current output level (0-15). Silencing the channel stops the
+    /// sequencer rather than forcing the output to 0, so it holds its level.
+    pub fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.sequence_pos as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Triangle;
+
+    #[test]
+    fn linear_counter_reload() {
+        let mut triangle = Triangle::default();
+        triangle.write_register(0, 0x05);
+        triangle.write_register(3, 0x00);
+        triangle.clock_quarter_frame();
+        assert_eq!(triangle.linear_counter, 5);
+        // The reload flag is cleared when the control flag is clear
+        triangle.clock_quarter_frame();
+        assert_eq!(triangle.linear_counter, 4);
+
+        triangle.write_register(0, 0x85);
+        triangle.write_register(3, 0x00);
+        for _ in 0..3 {
+            triangle.clock_quarter_frame();
+            assert_eq!(triangle.linear_counter, 5);
+        }
+    }
+}
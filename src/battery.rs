@@ -0,0 +1,125 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the path of the save file for the ROM at `rom_path`: its file name
+/// with a `.sav` extension, in `dir` if given or else next to the ROM.
+pub fn save_path(rom_path: &Path, dir: Option<&Path>) -> PathBuf {
+    let path = rom_path.with_extension("sav");
+    match (dir, path.file_name()) {
+        (Some(dir), Some(file_name)) => dir.join(file_name),
+        _ => path,
+    }
+}
+
+/// A `.sav` file holding a cartridge's battery-backed RAM.
+pub struct SaveFile {
+    path: PathBuf,
+    /// Contents of the file as of the last load or write, to skip writing it
+    /// again when nothing changed
+    saved: Vec<u8>,
+}
+
+impl SaveFile {
+    /// `ram` is the RAM's contents at power-on, which don't need writing.
+    pub fn new(path: PathBuf, ram: Vec<u8>) -> SaveFile {
+        SaveFile {
+            path: path,
+            saved: ram,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the file, or returns `None` if there is none yet.
+    pub fn load(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                self.saved = data.clone();
+                Ok(Some(data))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes `ram` to the file, unless it didn't change since the last load
+    /// or write.
+    ///
+    /// The data goes to a temporary file first, which then replaces the save
+    /// file, so that a crash while writing leaves the previous save intact.
+    pub fn write(&mut self, ram: &[u8]) -> io::Result<()> {
+        if ram == &self.saved[..] {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut temp_path = OsString::from(self.path.as_os_str());
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(ram)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, &self.path)?;
+        self.saved = ram.to_vec();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{save_path, SaveFile};
+
+    /// A fresh directory for a test's files
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("nes-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn save_path_next_to_rom_or_in_dir() {
+        let rom = Path::new("roms/game.nes");
+        assert_eq!(save_path(rom, None), Path::new("roms/game.sav"));
+        assert_eq!(
+            save_path(rom, Some(Path::new("saves"))),
+            Path::new("saves/game.sav")
+        );
+    }
+
+    #[test]
+    fn write_replaces_file() {
+        let dir = temp_dir("write");
+        let path = dir.join("game.sav");
+        let mut save_file = SaveFile::new(path.clone(), vec![0; 4]);
+        assert!(save_file.load().unwrap().is_none());
+
+        // Unchanged RAM isn't written
+        save_file.write(&[0; 4]).unwrap();
+        assert!(!path.exists());
+
+        save_file.write(&[1, 2, 3, 4]).unwrap();
+        save_file.write(&[5, 6, 7, 8]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [5, 6, 7, 8]);
+        let files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["game.sav"]);
+
+        let mut save_file = SaveFile::new(path, vec![0; 4]);
+        assert_eq!(save_file.load().unwrap(), Some(vec![5, 6, 7, 8]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
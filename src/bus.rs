@@ -1,3 +1,4 @@
+use crate::apu::Apu;
 use crate::controller::Controller;
 use crate::mapper::Mapper;
 use crate::ppu::Ppu;
@@ -5,53 +6,257 @@ use crate::ppu::Ppu;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+/// Ram Size
+const RAM_SIZE: usize = 0x800;
 
+/// The address and data buses a 6502 is connected to.
+pub trait Bus {
+    /// Reads a byte, with any side effects the read has on the device at
+    /// `addr` (e.g. clearing a status flag).
+    fn read(&mut self, addr: u16) -> u8;
+    /// Writes a byte.
+    fn write(&mut self, addr: u16, val: u8);
+    /// Returns the byte `read` would return, without any side effects. Used
+    /// by tracing and debuggers.
+    fn peek(&self, addr: u16) -> u8;
+}
+
+/// A flat 64 KB RAM bus with nothing else connected, for running the 6502
+/// core outside of a NES.
+pub struct RamBus {
+    pub ram: [u8; 0x10000],
+}
+
+impl RamBus {
+    pub fn new() -> RamBus {
+        RamBus { ram: [0; 0x10000] }
+    }
+}
+
+impl Default for RamBus {
+    fn default() -> RamBus {
+        RamBus::new()
+    }
+}
+
+impl Bus for RamBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.ram[addr as usize] = val;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+}
 
 /// Connects all the NES components
-pub struct Bus {
+pub struct NesBus {
     ram: [u8; RAM_SIZE],
     mapper: Rc<RefCell<Box<Mapper>>>,
-    pub controller: Controller,
+    /// The controllers plugged into ports 1 and 2
+    pub controllers: [Controller; 2],
     ppu: Rc<RefCell<Ppu>>,
+    apu: Rc<RefCell<Apu>>,
+    /// The last value driven on the data bus, returned when reading an
+    /// address nothing responds to.
+    open_bus: u8,
+    /// Set by a write to $4014 until the CPU has been stalled for the
+    /// transfer
+    oam_dma: bool,
+    /// Set by a write to $4017 until it has been forwarded to the APU, which
+    /// needs to know the cycle it happened on
+    frame_counter_write: Option<u8>,
+    /// The address of the last access, if it was a read
+    last_read: Option<u16>,
 }
 
-impl Bus {
+impl NesBus {
     pub fn new(
         mapper: Rc<RefCell<Box<Mapper>>>,
-        controller: Controller,
         ppu: Rc<RefCell<Ppu>>,
-    ) -> Bus {
-        Bus {
+        apu: Rc<RefCell<Apu>>,
+    ) -> NesBus {
+        NesBus {
             ram: [0; RAM_SIZE],
             mapper: mapper,
-            controller: controller,
+            controllers: [Controller::default(), Controller::default()],
             ppu: ppu,
+            apu: apu,
+            open_bus: 0,
+            oam_dma: false,
+            frame_counter_write: None,
+            last_read: None,
         }
     }
 
-    /// Implements the CPU's memory map
-    pub fn read(&mut self, addr: u16) -> u8 {
-        if addr < 0x2000 {
-            self.ram[addr as usize % RAM_SIZE]
-        } else if addr < 0x4000 {
-            self.ppu.borrow_mut().read_register(addr)
-        } else if addr >= 0x6000 {
-            self.mapper.borrow().read(addr)
-        } else if addr == 0x4016 {
-            self.controller.read()
-        } else {
-            unimplemented!()
+    /// Returns whether an OAM DMA transfer took place since the last call, in
+    /// which case the CPU has to be stalled for it.
+    pub fn take_oam_dma(&mut self) -> bool {
+        let dma = self.oam_dma;
+        self.oam_dma = false;
+        dma
+    }
+
+    /// Returns the value written to $4017 since the last call, if any.
+    pub fn take_frame_counter_write(&mut self) -> Option<u8> {
+        self.frame_counter_write.take()
+    }
+
+    /// Fetches a DMC sample byte.
+    ///
+    /// The DMA unit halts the CPU on a read cycle, which the CPU repeats
+    /// while halted. If `on_read` is set, i.e. the DMA landed on the last
+    /// cycle of the instruction, that read is replayed: when it was a
+    /// controller read, this clocks the controller's shift register once more
+    /// and drops a bit, as happens on hardware.
+    pub fn dmc_dma(&mut self, addr: u16, on_read: bool) -> u8 {
+        if on_read {
+            if let Some(last @ 0x4016...0x4017) = self.last_read {
+                self.read(last);
+            }
         }
+        self.read(addr)
+    }
+
+    /// Copies the 256-byte CPU memory page `page` to the PPU's OAM.
+    fn oam_dma(&mut self, page: u8) {
+        let base = u16::from(page) << 8;
+        for i in 0..0x100 {
+            let val = self.read(base | i);
+            self.ppu.borrow_mut().write_oam(val);
+        }
+        self.oam_dma = true;
+    }
+}
+
+impl Bus for NesBus {
+    /// Implements the CPU's memory map
+    ///
+    /// * $0000-$1FFF: 2 KB internal RAM, mirrored every $800 bytes
+    /// * $2000-$3FFF: PPU registers, mirrored every 8 bytes
+    /// * $4000-$4017: APU and I/O registers
+    /// * $4018-$401F: APU and I/O test mode, normally disabled
+    /// * $4020-$5FFF: Expansion space
+    /// * $6000-$FFFF: Cartridge space (PRG-RAM and PRG-ROM)
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = match addr {
+            0x0000...0x1FFF => self.ram[addr as usize % RAM_SIZE],
+            0x2000...0x3FFF => self.ppu.borrow_mut().read_register(addr),
+            // Reading $4015 does not update the data bus, and bit 5 is not
+            // driven
+            0x4015 => {
+                self.last_read = Some(addr);
+                return (self.open_bus & 0x20) | self.apu.borrow_mut().read_status();
+            }
+            // Only bit 0 is driven by the controllers, the rest is open bus
+            0x4016 => (self.open_bus & 0xE0) | self.controllers[0].read(),
+            0x4017 => (self.open_bus & 0xE0) | self.controllers[1].read(),
+            0x4020...0x5FFF => self
+                .mapper
+                .borrow_mut()
+                .expansion_read(addr)
+                .unwrap_or(self.open_bus),
+            0x4000...0x401F => self.open_bus,
+            _ => self.mapper.borrow_mut().cpu_read(addr),
+        };
+        self.open_bus = val;
+        self.last_read = Some(addr);
+        val
     }
 
     /// Implements the CPU's memory map
-    pub fn write(&mut self, addr: u16, val: u8) {
-        if addr < 0x2000 {
-            self.ram[(addr % 0x800) as usize] = val;
-        } else if addr >= 0x6000 {
-            self.mapper.borrow_mut().write(addr, val);
-        } else if addr == 0x4016 {
-            self.controller.write(val);
+    fn write(&mut self, addr: u16, val: u8) {
+        self.open_bus = val;
+        self.last_read = None;
+        match addr {
+            0x0000...0x1FFF => self.ram[addr as usize % RAM_SIZE] = val,
+            0x2000...0x3FFF => {
+                self.ppu.borrow_mut().write_register(addr, val);
+                // Some boards watch the PPU registers (e.g. the MMC5 needs
+                // the sprite size)
+                self.mapper.borrow_mut().cpu_write(addr, val);
+            }
+            0x4014 => self.oam_dma(val),
+            0x4016 => {
+                self.controllers[0].write(val);
+                self.controllers[1].write(val);
+            }
+            0x4017 => self.frame_counter_write = Some(val),
+            0x4000...0x4013 | 0x4015 => self.apu.borrow_mut().write_register(addr, val),
+            0x4000...0x401F => {}
+            _ => self.mapper.borrow_mut().cpu_write(addr, val),
         }
     }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x1FFF => self.ram[addr as usize % RAM_SIZE],
+            0x2000...0x3FFF => self.ppu.borrow().peek_register(addr),
+            0x4015 => (self.open_bus & 0x20) | self.apu.borrow().peek_status(),
+            0x4016 => (self.open_bus & 0xE0) | self.controllers[0].peek(),
+            0x4017 => (self.open_bus & 0xE0) | self.controllers[1].peek(),
+            0x4020...0x5FFF => self
+                .mapper
+                .borrow()
+                .expansion_peek(addr)
+                .unwrap_or(self.open_bus),
+            0x4000...0x401F => self.open_bus,
+            _ => self.mapper.borrow().peek(addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{Bus, NesBus};
+    use crate::apu::Apu;
+    use crate::mapper;
+    use crate::ppu::Ppu;
+    use crate::rom::Rom;
+
+    fn bus() -> NesBus {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x00, 0x00];
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        let rom = Rom::load(&mut &image[..]).unwrap();
+        let mapper = Rc::new(RefCell::new(mapper::init(rom)));
+        let ppu = Rc::new(RefCell::new(Ppu::new(mapper.clone())));
+        let apu = Rc::new(RefCell::new(Apu::new()));
+        NesBus::new(mapper, ppu, apu)
+    }
+
+    /// Presses A and Select and latches the buttons.
+    fn strobe_controller(bus: &mut NesBus) {
+        bus.controllers[0].buttons.set_a(true);
+        bus.controllers[0].buttons.set_select(true);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+    }
+
+    #[test]
+    fn dmc_dma_on_controller_read_drops_a_bit() {
+        let mut bus = bus();
+        strobe_controller(&mut bus);
+        assert_eq!(bus.read(0x4016) & 1, 1);
+        // The replayed read clocks the shift register past B
+        bus.dmc_dma(0xC000, true);
+        assert_eq!(bus.read(0x4016) & 1, 1);
+    }
+
+    #[test]
+    fn dmc_dma_between_controller_reads() {
+        let mut bus = bus();
+        strobe_controller(&mut bus);
+        assert_eq!(bus.read(0x4016) & 1, 1);
+        bus.dmc_dma(0xC000, false);
+        assert_eq!(bus.read(0x4016) & 1, 0);
+        assert_eq!(bus.read(0x4016) & 1, 1);
+    }
 }
@@ -3,6 +3,7 @@ use bitfield::{Bit, BitRange, bitfield};
 bitfield!{
     /// Represents the statuses of the buttons. Every button is represented by
     /// one bit with
+    #[derive(Copy, Clone)]
     pub struct Buttons(u8);
     impl Debug;
     pub a, set_a: 0;
@@ -40,12 +41,18 @@ impl Controller {
         self.buttons.bit_range(7, 0)
     }
 
-    pub fn read(&mut self) -> u8 {
-        let val = if self.index < 8 && self.buttons.bit(self.index as usize) {
+    /// Returns the bit `read` would return, without shifting to the next
+    /// button.
+    pub fn peek(&self) -> u8 {
+        if self.index < 8 && self.buttons.bit(self.index as usize) {
             1
         } else {
             0
-        };
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        let val = self.peek();
 
         self.index += 1;
 
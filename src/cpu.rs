@@ -3,12 +3,7 @@ use bitfield::BitRange;
 use log::Level::Debug;
 use log::{info, log_enabled};
 
-use crate::controller::Controller;
-use crate::mapper::{init, Mapper};
-use crate::ppu::Ppu;
-
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::bus::Bus;
 
 /// Stack offset
 const STACK: u16 = 0x100;
@@ -19,9 +14,6 @@ const RESET_VECTOR: u16 = 0xFFFC;
 /// IRQ/BRK vector
 const IRQ_BRK_VECTOR: u16 = 0xFFFE;
 
-/// Ram Size
-const RAM_SIZE: usize = 0x800;
-
 /// Instruction mode corresponding to each opcode as resolved by the `resolve_address` function.
 static INSTRUCTION_MODES: [usize; 256] = [
     6, 7, 6, 7, 11, 11, 11, 11, 6, 5, 4, 5, 1, 1, 1, 1, 10, 9, 6, 9, 12, 12, 12, 12, 6, 3, 6, 3, 2,
@@ -94,10 +86,18 @@ static INSTRUCTION_NAMES: [&str; 256] = [
     "ISC", "SED", "SBC", "NOP", "ISC", "NOP", "SBC", "INC", "ISC",
 ];
 
-enum Interrupt {
-    IRQ,
-    NMI,
-    None,
+/// Devices that can hold the CPU's /IRQ line low.
+///
+/// The line is level-triggered and wired-OR: it stays asserted as long as any
+/// source asserts it, and each source has to be acknowledged on its own.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IrqSource {
+    /// The APU frame counter
+    FrameCounter = 0x01,
+    /// The APU delta modulation channel
+    Dmc = 0x02,
+    /// The cartridge mapper (e.g. a scanline or cycle counter)
+    Mapper = 0x04,
 }
 
 bitfield! {
@@ -112,16 +112,26 @@ bitfield! {
     pub get_n, set_n: 7;
 }
 
-/// The CPU struct
-pub struct Cpu {
-    
-    ram: [u8; RAM_SIZE],
-    ppu: Rc<RefCell<Ppu>>,
-    mapper: Rc<RefCell<Box<Mapper>>>,
-    pub controller: Controller,
+/// The CPU struct, generic over the `Bus` it is connected to.
+pub struct Cpu<B: Bus> {
+    /// The CPU's view of the rest of the system
+    pub bus: B,
     cycles: usize, // Cycles remaining
     stall: usize,  // Cycles to stall the CPU for (for catch-up)
-    interrupt: Interrupt,
+    /// Sources currently asserting /IRQ, as a mask of `IrqSource`s
+    irq_line: u8,
+    /// Value of the I flag when interrupts were last polled. CLI, SEI and PLP
+    /// only change the I flag after the poll, which delays their effect by
+    /// one instruction.
+    irq_inhibit: bool,
+    /// Last sampled level of the /NMI input (true when asserted)
+    nmi_line: bool,
+    /// An NMI edge was detected in time to be serviced before the next
+    /// instruction
+    nmi_pending: bool,
+    /// An NMI edge was detected during the last cycle of an instruction, too
+    /// late for that instruction's interrupt poll
+    nmi_delayed: bool,
     // Registers
     pc: u16,
     sp: u8,
@@ -131,17 +141,17 @@ pub struct Cpu {
     p: ProcessorStatus, // The status register is made up of 5 flags and 3 unused bits
 }
 
-impl Cpu {
-    pub fn new(mapper: Rc<RefCell<Box<Mapper>>>, controller: Controller, ppu: Rc<RefCell<Ppu>>) -> Cpu {
+impl<B: Bus> Cpu<B> {
+    pub fn new(bus: B) -> Cpu<B> {
         Cpu {
-            ppu: ppu,
-            mapper: mapper,
-            controller: controller,
-
-            ram: [0; RAM_SIZE],
+            bus: bus,
             cycles: 0,
             stall: 0,
-            interrupt: Interrupt::None,
+            irq_line: 0,
+            irq_inhibit: true,
+            nmi_line: false,
+            nmi_pending: false,
+            nmi_delayed: false,
             pc: 0xC000,
             sp: 0xFD,
             a: 0,
@@ -163,45 +173,70 @@ impl Cpu {
         )
     }
 
+    /// Total number of cycles executed
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Suspends execution for `cycles` cycles, e.g. while a DMA unit uses the
+    /// bus.
+    pub fn stall(&mut self, cycles: usize) {
+        self.stall += cycles;
+    }
+
+    /// Whether the CPU is currently suspended by `stall`
+    pub fn stalled(&self) -> bool {
+        self.stall > 0
+    }
+
     pub fn reset(&mut self) {
         self.p.set_bit_range(7, 0, 0x24);
         self.sp = 0xFD;
+        self.irq_inhibit = true;
         self.pc = self.read16(RESET_VECTOR)
     }
 
-    pub fn trigger_nmi(&mut self) {
-        self.interrupt = Interrupt::NMI;
+    /// Samples the /NMI input, which should be done once per CPU cycle.
+    ///
+    /// NMI is edge-triggered: only a transition from inactive to active
+    /// requests an interrupt. The CPU polls for interrupts before the last
+    /// cycle of each instruction, so an edge seen on that `last_cycle` is
+    /// only serviced after the following instruction.
+    pub fn set_nmi(&mut self, active: bool, last_cycle: bool) {
+        if active && !self.nmi_line {
+            if last_cycle {
+                self.nmi_delayed = true;
+            } else {
+                self.nmi_pending = true;
+            }
+        }
+        self.nmi_line = active;
     }
 
-    pub fn trigger_irq(&mut self) {
-        if self.p.get_i() {
-            self.interrupt = Interrupt::IRQ;
+    /// Asserts or releases the /IRQ line on behalf of `source`.
+    pub fn set_irq(&mut self, source: IrqSource, asserted: bool) {
+        if asserted {
+            self.irq_line |= source as u8;
+        } else {
+            self.irq_line &= !(source as u8);
         }
     }
 
     fn read(&mut self, addr: u16) -> u8 {
-        if addr < 0x2000 {
-            self.ram[addr as usize % RAM_SIZE]
-        } else if addr < 0x4000 {
-            self.ppu.borrow_mut().read_register(addr)
-            // unimplemented!()
-        } else if addr >= 0x6000 {
-            self.mapper.borrow_mut().read(addr)
-        } else if addr == 0x4016 {
-            self.controller.read()
-        } else {
-            unimplemented!()
-        }
+        self.bus.read(addr)
     }
 
     fn write(&mut self, addr: u16, val: u8) {
-        if addr < 0x2000 {
-            self.ram[(addr % 0x800) as usize] = val;
-        } else if addr >= 0x6000 {
-            self.mapper.borrow_mut().write(addr, val);
-        } else if addr == 0x4016 {
-            self.controller.write(val);
-        }
+        self.bus.write(addr, val);
+    }
+
+    /// Reads the operand of a read-modify-write instruction. The 6502 writes
+    /// the unmodified value back on the cycle before writing the result,
+    /// which some hardware (e.g. the MMC1) reacts to.
+    fn read_modify_write(&mut self, addr: u16) -> u8 {
+        let m = self.read(addr);
+        self.write(addr, m);
+        m
     }
 
     // Util
@@ -289,20 +324,24 @@ impl Cpu {
     pub fn step(&mut self) -> isize {
         if self.stall > 0 {
             self.stall -= 1;
+            self.cycles += 1;
             return 1;
         }
 
         self.trace();
 
-        match self.interrupt {
-            Interrupt::IRQ => self.irq(),
-            Interrupt::NMI => self.nmi(),
-            Interrupt::None => {}
-        }
-        self.interrupt = Interrupt::None;
-
         let cy = self.cycles;
 
+        let nmi = self.nmi_pending;
+        self.nmi_pending = self.nmi_delayed;
+        self.nmi_delayed = false;
+
+        if nmi {
+            self.nmi();
+        } else if self.irq_line != 0 && !self.irq_inhibit {
+            self.irq();
+        }
+
         let opcode = self.read(self.pc);
         let cycles = INSTRUCTION_CYCLES[opcode as usize];
 
@@ -311,19 +350,26 @@ impl Cpu {
         self.pc += INSTRUCTION_SIZES[opcode as usize] as u16;
         self.cycles += cycles;
 
+        let i = self.p.get_i();
         self.exec(opcode, addressing_mode);
 
+        self.irq_inhibit = match opcode {
+            // CLI, SEI, PLP
+            0x58 | 0x78 | 0x28 => i,
+            _ => self.p.get_i(),
+        };
+
         (self.cycles - cy) as isize
     }
 
     /// Logs the current state of the CPU.
-    fn trace(&mut self) {
-        let opcode = self.read(self.pc) as usize;
+    fn trace(&self) {
+        let opcode = self.bus.peek(self.pc) as usize;
         let bytes = INSTRUCTION_SIZES[opcode];
         let name = INSTRUCTION_NAMES[opcode];
-        let first_byte = format!("{:02X}", self.read(self.pc));
-        let mut second_byte = format!("{:02X}", self.read(self.pc + 1));
-        let mut third_byte = format!("{:02X}", self.read(self.pc + 2));
+        let first_byte = format!("{:02X}", self.bus.peek(self.pc));
+        let mut second_byte = format!("{:02X}", self.bus.peek(self.pc + 1));
+        let mut third_byte = format!("{:02X}", self.bus.peek(self.pc + 2));
         if bytes < 2 {
             second_byte = String::from("  ");
         }
@@ -359,7 +405,7 @@ impl Cpu {
 
     fn absolute_x(&mut self, opcode: u8) -> u16 {
         let addr = self.read16(self.pc + 1) + u16::from(self.x);
-        if Cpu::check_same_page(addr - u16::from(self.x), addr) {
+        if Self::check_same_page(addr - u16::from(self.x), addr) {
             self.cycles += CYCLES_PAGE_CROSS[opcode as usize];
         }
         addr
@@ -367,7 +413,7 @@ impl Cpu {
 
     fn absolute_y(&mut self, opcode: u8) -> u16 {
         let addr = self.read16(self.pc + 1) + u16::from(self.y);
-        if Cpu::check_same_page(addr - u16::from(self.y), addr) {
+        if Self::check_same_page(addr - u16::from(self.y), addr) {
             self.cycles += CYCLES_PAGE_CROSS[opcode as usize];
         }
         addr
@@ -390,7 +436,7 @@ impl Cpu {
     fn indirect_indexed(&mut self, opcode: u8) -> u16 {
         let addr = self.read(self.pc + 1);
         let addr = self.read16_wrap(u16::from(addr)) + u16::from(self.y);
-        if Cpu::check_same_page(addr - u16::from(self.y), addr) {
+        if Self::check_same_page(addr - u16::from(self.y), addr) {
             self.cycles += CYCLES_PAGE_CROSS[opcode as usize];
         }
         addr
@@ -615,19 +661,21 @@ impl Cpu {
     }
 
     fn nmi(&mut self) {
-        let pc = self.pc;
-        self.push16(pc);
-        self.php();
-        self.pc = self.read16(NMI_VECTOR);
-        self.p.set_i(true);
-        self.cycles += 7;
+        self.interrupt_sequence(NMI_VECTOR);
     }
 
     fn irq(&mut self) {
+        self.interrupt_sequence(IRQ_BRK_VECTOR);
+    }
+
+    /// Pushes the program counter and the status register (with the B flag
+    /// clear, unlike BRK and PHP) and jumps through `vector`.
+    fn interrupt_sequence(&mut self, vector: u16) {
         let pc = self.pc;
         self.push16(pc);
-        self.php();
-        self.pc = self.read16(IRQ_BRK_VECTOR);
+        let p: u8 = self.p.bit_range(7, 0);
+        self.push(p & 0xEF | 0x20);
+        self.pc = self.read16(vector);
         self.p.set_i(true);
         self.cycles += 7;
     }
@@ -670,7 +718,7 @@ impl Cpu {
 
     // ASL - Arithmetic Shift Left
     fn asl(&mut self, addr: u16) {
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.p.set_c((m >> 7) & 1 == 1);
         self.write(addr, m << 1);
         self.check_negative_zero(m << 1);
@@ -682,7 +730,7 @@ impl Cpu {
     fn bcc(&mut self, addr: u16) {
         if !self.p.get_c() {
             self.cycles += 1;
-            if Cpu::check_same_page(self.pc, addr) {
+            if Self::check_same_page(self.pc, addr) {
                 self.cycles += 1;
             }
             self.pc = addr;
@@ -693,7 +741,7 @@ impl Cpu {
     fn bcs(&mut self, addr: u16) {
         if self.p.get_c() {
             self.cycles += 1;
-            if Cpu::check_same_page(self.pc, addr) {
+            if Self::check_same_page(self.pc, addr) {
                 self.cycles += 1;
             }
             self.pc = addr;
@@ -704,7 +752,7 @@ impl Cpu {
     fn beq(&mut self, addr: u16) {
         if self.p.get_z() {
             self.cycles += 1;
-            if Cpu::check_same_page(self.pc, addr) {
+            if Self::check_same_page(self.pc, addr) {
                 self.cycles += 1;
             }
             self.pc = addr;
@@ -724,7 +772,7 @@ impl Cpu {
     fn bmi(&mut self, addr: u16) {
         if self.p.get_n() {
             self.cycles += 1;
-            if Cpu::check_same_page(self.pc, addr) {
+            if Self::check_same_page(self.pc, addr) {
                 self.cycles += 1;
             }
             self.pc = addr;
@@ -735,7 +783,7 @@ impl Cpu {
     fn bne(&mut self, addr: u16) {
         if !self.p.get_z() {
             self.cycles += 1;
-            if Cpu::check_same_page(self.pc, addr) {
+            if Self::check_same_page(self.pc, addr) {
                 self.cycles += 1;
             }
             self.pc = addr;
@@ -746,7 +794,7 @@ impl Cpu {
     fn bpl(&mut self, addr: u16) {
         if !self.p.get_n() {
             self.cycles += 1;
-            if Cpu::check_same_page(self.pc, addr) {
+            if Self::check_same_page(self.pc, addr) {
                 self.cycles += 1;
             }
             self.pc = addr;
@@ -766,7 +814,7 @@ impl Cpu {
     fn bvc(&mut self, addr: u16) {
         if !self.p.get_v() {
             self.cycles += 1;
-            if Cpu::check_same_page(self.pc, addr) {
+            if Self::check_same_page(self.pc, addr) {
                 self.cycles += 1;
             }
             self.pc = addr;
@@ -777,7 +825,7 @@ impl Cpu {
     fn bvs(&mut self, addr: u16) {
         if self.p.get_v() {
             self.cycles += 1;
-            if Cpu::check_same_page(self.pc, addr) {
+            if Self::check_same_page(self.pc, addr) {
                 self.cycles += 1;
             }
             self.pc = addr;
@@ -816,7 +864,7 @@ impl Cpu {
 
     /// DEC - Decrement Memory
     fn dec(&mut self, addr: u16) {
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.write(addr, m - 1);
         self.check_negative_zero(m - 1);
     }
@@ -844,7 +892,7 @@ impl Cpu {
 
     /// INC - Increment Memory
     fn inc(&mut self, addr: u16) {
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.write(addr, m + 1);
         self.check_negative_zero(m + 1);
     }
@@ -906,7 +954,7 @@ impl Cpu {
 
     /// LSR - Logical Shift Right
     fn lsr(&mut self, addr: u16) {
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.p.set_c(m & 1 == 1);
         self.write(addr, m >> 1);
         self.check_negative_zero(m >> 1);
@@ -956,7 +1004,7 @@ impl Cpu {
     /// ROL - Rotate Left
     fn rol(&mut self, addr: u16) {
         let c = self.p.get_c() as u8;
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.p.set_c((m >> 7) & 1 == 1);
         self.write(addr, (m << 1) | c);
         self.check_negative_zero((m << 1) | c);
@@ -974,7 +1022,7 @@ impl Cpu {
     /// ROR - Rotate Right
     fn ror(&mut self, addr: u16) {
         let c = self.p.get_c() as u8;
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.p.set_c(m & 1 == 1);
         self.write(addr, (m >> 1) | (c << 7));
         self.check_negative_zero((m >> 1) | (c << 7));
@@ -1112,7 +1160,7 @@ impl Cpu {
 
     /// SLO - Shift Left and OR (with accumulator)
     fn slo(&mut self, addr: u16) {
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.p.set_c((m >> 7) & 1 == 1);
         let val = m << 1;
         self.a |= val;
@@ -1123,7 +1171,7 @@ impl Cpu {
     /// RLA - Rotate Left then AND (with accumulator)
     fn rla(&mut self, addr: u16) {
         let c = self.p.get_c() as u8;
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.p.set_c((m >> 7) & 1 == 1);
         let val = (m << 1) | c;
         self.a &= val;
@@ -1133,7 +1181,7 @@ impl Cpu {
 
     /// SRE - Shift Right then EOR (XOR) (with accumulator)
     fn sre(&mut self, addr: u16) {
-        let m = self.read(addr);
+        let m = self.read_modify_write(addr);
         self.p.set_c(m & 1 == 1);
         let val = m >> 1;
         self.a ^= val;
@@ -1162,8 +1210,11 @@ mod tests {
     use std::path::Path;
     use std::rc::Rc;
 
-    use super::{Controller, Cpu, Mapper, Ppu};
+    use super::{Cpu, IrqSource};
+    use crate::apu::Apu;
+    use crate::bus::{Bus, NesBus, RamBus};
     use crate::mapper;
+    use crate::ppu::Ppu;
     use crate::rom::Rom;
 
     #[test]
@@ -1172,9 +1223,9 @@ mod tests {
         let rom = Rom::load(&mut File::open(&path).unwrap()).unwrap();
         let mapper = mapper::init(rom);
         let mut mapper = Rc::new(RefCell::new(mapper));
-        let controller = Controller::default();
         let ppu = Rc::new(RefCell::new(Ppu::new(mapper.clone())));
-        let mut cpu = Cpu::new(mapper.clone(), controller, ppu);
+        let apu = Rc::new(RefCell::new(Apu::new()));
+        let mut cpu = Cpu::new(NesBus::new(mapper.clone(), ppu, apu));
 
         let file = File::open("test_roms/nestest.log").unwrap();
         let buf_reader = BufReader::new(file);
@@ -1233,4 +1284,54 @@ mod tests {
             cpu.step();
         }
     }
+
+    /// Loads `program` at $8000 on a flat RAM bus and points the reset
+    /// vector at it.
+    fn ram_cpu(program: &[u8]) -> Cpu<RamBus> {
+        let mut bus = RamBus::new();
+        bus.ram[0x8000..0x8000 + program.len()].copy_from_slice(program);
+        bus.write(0xFFFC, 0x00);
+        bus.write(0xFFFD, 0x80);
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn adc_signed_overflow() {
+        // LDA #$50; ADC #$50; STA $0200
+        let mut cpu = ram_cpu(&[0xA9, 0x50, 0x69, 0x50, 0x8D, 0x00, 0x02]);
+        for _ in 0..3 {
+            cpu.step();
+        }
+
+        let (pc, _, a, _, _, p, _) = cpu.registers();
+        assert_eq!(pc, 0x8007);
+        assert_eq!(a, 0xA0);
+        assert_eq!(cpu.bus.peek(0x0200), 0xA0);
+        // N and V set, C and Z clear
+        assert_eq!(p & 0xC3, 0xC0);
+    }
+
+    #[test]
+    fn cli_delays_irq_by_one_instruction() {
+        // CLI; NOP; NOP
+        let mut cpu = ram_cpu(&[0x58, 0xEA, 0xEA]);
+        // IRQ handler at $9000: NOP
+        cpu.bus.write(0x9000, 0xEA);
+        cpu.bus.write(0xFFFE, 0x00);
+        cpu.bus.write(0xFFFF, 0x90);
+        cpu.set_irq(IrqSource::Mapper, true);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers().0, 0x8002);
+
+        cpu.step();
+        assert_eq!(cpu.registers().0, 0x9001);
+        // Return address and status with B clear
+        assert_eq!(cpu.bus.peek(0x01FD), 0x80);
+        assert_eq!(cpu.bus.peek(0x01FC), 0x02);
+        assert_eq!(cpu.bus.peek(0x01FB) & 0x30, 0x20);
+    }
 }
@@ -5,30 +5,52 @@ use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
 
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::PixelFormatEnum;
 
+pub mod apu;
+pub mod battery;
+pub mod bus;
 pub mod controller;
 pub mod cpu;
 pub mod mapper;
+pub mod nes;
 pub mod ppu;
 pub mod rom;
+pub mod state;
+pub mod wav;
 
 #[macro_use]
 pub mod util;
 
-use crate::controller::Controller;
-use crate::cpu::Cpu;
-use crate::ppu::Ppu;
+use crate::battery::SaveFile;
+use crate::controller::Buttons;
 use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
 use crate::rom::Rom;
 
-use std::cell::RefCell;
-use std::fs::File;
-use std::rc::Rc;
+pub use crate::nes::Nes;
+
+use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Sample rate requested from the audio device, in Hz. The device may pick
+/// another one (e.g. 44100 Hz).
+const AUDIO_SAMPLE_RATE: i32 = 48_000;
+
+/// Number of samples the audio queue should hold, i.e. the audio latency
+/// (about 3 frames)
+const AUDIO_TARGET_QUEUED: f64 = AUDIO_SAMPLE_RATE as f64 / 20.0;
+
+/// Maximum relative adjustment of the sample rate by dynamic rate control.
+/// 0.5% is not audible as a change of pitch.
+const AUDIO_MAX_RATE_DELTA: f64 = 0.005;
+
+/// Frames between writes of the battery-backed RAM (about 5 seconds), so
+/// that little is lost if the emulator doesn't exit cleanly
+const AUTOSAVE_INTERVAL: usize = 300;
+
 /// Initializes and configures logging using log4rs
 fn init_logging() {
     let logfile = FileAppender::builder()
@@ -44,8 +66,124 @@ fn init_logging() {
     log4rs::init_config(config).unwrap();
 }
 
+/// Command line options
+#[derive(Default)]
+pub struct Options {
+    /// WAV file to record the audio output to
+    pub record_audio: Option<PathBuf>,
+    /// Also record each sound channel to its own file
+    pub record_channels: bool,
+    /// Run headlessly (without a window or sound) for this many frames
+    pub frames: Option<usize>,
+    /// File to load the battery-backed RAM from and save it to
+    pub save_file: Option<PathBuf>,
+}
+
+/// Updates `buttons` for a key press or release of `keycode`
+fn map_key(buttons: &mut Buttons, keycode: Keycode, pressed: bool) {
+    match keycode {
+        Keycode::Z => buttons.set_a(pressed),
+        Keycode::X => buttons.set_b(pressed),
+        Keycode::Backspace => buttons.set_select(pressed),
+        Keycode::Return => buttons.set_start(pressed),
+        Keycode::Up => buttons.set_up(pressed),
+        Keycode::Down => buttons.set_down(pressed),
+        Keycode::Left => buttons.set_left(pressed),
+        Keycode::Right => buttons.set_right(pressed),
+        _ => {}
+    }
+}
+
+/// Dynamic rate control: returns the sample rate to generate the next frame
+/// at, so that the audio queue stays around `AUDIO_TARGET_QUEUED` samples.
+///
+/// The display (with vsync) and the audio device run on separate clocks, and
+/// the NES's frame rate (60.1 Hz) doesn't exactly match either. Producing
+/// slightly fewer samples when the queue is filling up, and slightly more
+/// when it is draining, keeps it from over- or under-running.
+fn adjusted_sample_rate(queue: &AudioQueue<f32>) -> f64 {
+    let freq = f64::from(queue.spec().freq);
+    let queued = f64::from(queue.size()) / std::mem::size_of::<f32>() as f64;
+    let target = AUDIO_TARGET_QUEUED * freq / AUDIO_SAMPLE_RATE as f64;
+    let delta = ((target - queued) / target).clamp(-1.0, 1.0);
+    freq * (1.0 + AUDIO_MAX_RATE_DELTA * delta)
+}
+
+/// Loads the battery-backed RAM from the save file, if the cartridge has
+/// any and a save file is configured. Returns the file to save it to.
+fn open_save_file(nes: &mut Nes, options: &Options) -> Option<SaveFile> {
+    let path = match &options.save_file {
+        Some(path) if nes.has_battery() => path.clone(),
+        _ => return None,
+    };
+    let mut save_file = SaveFile::new(path, nes.battery_ram());
+    match save_file.load() {
+        Ok(Some(ram)) => {
+            println!("Loaded save: {}", save_file.path().display());
+            nes.load_battery_ram(&ram);
+        }
+        Ok(None) => {}
+        Err(err) => {
+            // Don't overwrite a save that couldn't be read
+            eprintln!("Could not load {}: {}", save_file.path().display(), err);
+            return None;
+        }
+    }
+    Some(save_file)
+}
+
+/// Writes the battery-backed RAM to the save file if it changed. Errors are
+/// only reported, so that the game can go on.
+fn write_save_file(nes: &Nes, save_file: &mut Option<SaveFile>) {
+    if let Some(save_file) = save_file {
+        if let Err(err) = save_file.write(&nes.battery_ram()) {
+            eprintln!("Could not save {}: {}", save_file.path().display(), err);
+        }
+    }
+}
+
+/// Starts recording audio to the file given in the options, if any. Errors
+/// are only reported, so that the game can go on without recording. Returns
+/// whether the audio is being recorded.
+fn start_recording(nes: &mut Nes, options: &Options) -> bool {
+    match &options.record_audio {
+        Some(path) => match nes.start_recording(path, options.record_channels) {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("Could not record {}: {}", path.display(), err);
+                false
+            }
+        },
+        None => false,
+    }
+}
+
+/// Finishes the audio recording, if any, reporting errors.
+fn stop_recording(nes: &mut Nes, options: &Options) {
+    if let Err(err) = nes.stop_recording() {
+        if let Some(path) = &options.record_audio {
+            eprintln!("Could not record {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Runs the emulator without a window or audio device for `frames` frames,
+/// recording audio if requested.
+fn run_headless(mut nes: Nes, frames: usize, options: &Options, mut save_file: Option<SaveFile>) {
+    start_recording(&mut nes, options);
+    for frame in 1..=frames {
+        nes.step_frame();
+        nes.take_audio_samples();
+        if frame % AUTOSAVE_INTERVAL == 0 {
+            write_save_file(&nes, &mut save_file);
+        }
+    }
+    write_save_file(&nes, &mut save_file);
+    stop_recording(&mut nes, options);
+}
+
 /// Starts the emulator
-pub fn start(rom: Rom) {
+pub fn start(rom: Rom, options: Options) {
     // let rom = Box::new(rom);
 
     let current_time = SystemTime::now();
@@ -61,23 +199,32 @@ pub fn start(rom: Rom) {
 
     println!("Loaded ROM: {}", rom.header);
 
-    let mapper = mapper::init(rom);
-    let mut mapper = Rc::new(RefCell::new(mapper));
-
-    let mut ppu = Ppu::new(mapper.clone());
-    let mut ppu = Rc::new(RefCell::new(ppu));
-    let controller = Controller::default();
-
-    let mut cpu = Cpu::new(mapper.clone(), controller, ppu.clone());
-
-    cpu.reset();
+    let mut nes = Nes::from_rom(rom);
+    let mut save_file = open_save_file(&mut nes, &options);
+    if let Some(frames) = options.frames {
+        run_headless(nes, frames, &options, save_file);
+        return;
+    }
+    let mut buttons = Buttons::default();
 
     let sdl_context = sdl2::init().unwrap();
     sdl_context.mouse().show_cursor(false);
 
     let video_subsystem = sdl_context.video().unwrap();
 
-    // let audio_subsystem = sdl_context.audio().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let desired_spec = AudioSpecDesired {
+        freq: Some(AUDIO_SAMPLE_RATE),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+    let audio_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+    audio_queue.resume();
+
+    if options.record_audio.is_some() {
+        nes.set_sample_rate(f64::from(audio_queue.spec().freq));
+    }
+    let recording = start_recording(&mut nes, &options);
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
@@ -103,6 +250,7 @@ pub fn start(rom: Rom) {
             SCREEN_HEIGHT as u32,
         ).unwrap();
 
+    let mut frame = 0;
     'running: loop {
         for event in event_pump.poll_iter() {
             match event {
@@ -114,46 +262,35 @@ pub fn start(rom: Rom) {
                 Event::KeyDown {
                     keycode: Some(keycode),
                     ..
-                } => match keycode {
-                    Keycode::Z => cpu.controller.buttons.set_a(true),
-                    Keycode::X => cpu.controller.buttons.set_b(true),
-                    Keycode::Backspace => cpu.controller.buttons.set_select(true),
-                    Keycode::Return => cpu.controller.buttons.set_start(true),
-                    Keycode::Up => cpu.controller.buttons.set_up(true),
-                    Keycode::Down => cpu.controller.buttons.set_down(true),
-                    Keycode::Left => cpu.controller.buttons.set_left(true),
-                    Keycode::Right => cpu.controller.buttons.set_right(true),
-                    _ => {}
-                },
+                } => map_key(&mut buttons, keycode, true),
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
-                } => match keycode {
-                    Keycode::Z => cpu.controller.buttons.set_a(false),
-                    Keycode::X => cpu.controller.buttons.set_b(false),
-                    Keycode::Backspace => cpu.controller.buttons.set_select(false),
-                    Keycode::Return => cpu.controller.buttons.set_start(false),
-                    Keycode::Up => cpu.controller.buttons.set_up(false),
-                    Keycode::Down => cpu.controller.buttons.set_down(false),
-                    Keycode::Left => cpu.controller.buttons.set_left(false),
-                    Keycode::Right => cpu.controller.buttons.set_right(false),
-                    _ => {}
-                },
+                } => map_key(&mut buttons, keycode, false),
                 _ => {}
             }
         }
-        canvas.present();
-        let cpu_cycles = cpu.step();
-
-        // for _ in 0..cpu_cycles {
-        //     ppu.borrow_mut().step();
-        // }
+        nes.set_buttons(0, buttons);
+        // The recording's sample rate is fixed in its header, so the rate
+        // stays at the device's while recording.
+        if !recording {
+            nes.set_sample_rate(adjusted_sample_rate(&audio_queue));
+        }
+        nes.step_frame();
+        audio_queue.queue(&nes.take_audio_samples());
+        frame += 1;
+        if frame % AUTOSAVE_INTERVAL == 0 {
+            write_save_file(&nes, &mut save_file);
+        }
 
-        // texture
-        //     .update(None, &ppu.borrow_mut().screen, SCREEN_WIDTH as usize * 3)
-        //     .unwrap();
+        texture
+            .update(None, &nes.framebuffer(), SCREEN_WIDTH * 3)
+            .unwrap();
         canvas.clear();
         canvas.copy(&texture, None, None).unwrap();
         canvas.present();
     }
+
+    write_save_file(&nes, &mut save_file);
+    stop_recording(&mut nes, &options);
 }
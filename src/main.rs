@@ -1,22 +1,42 @@
-
+use nes::battery;
 use nes::rom::Rom;
-use nes::start;
+use nes::{start, Options};
 
 use std::env;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "Usage: nes <rom-path> [--record-audio <wav-path>] [--record-channels] \
+                     [--frames <count>] [--bus-conflicts] [--save-dir <dir>]";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args = env::args().skip(1);
+    let mut path = None;
+    let mut options = Options::default();
+    let mut bus_conflicts = false;
+    let mut save_dir = None;
 
-    if args.len() == 1 {
-        panic!("Usage: nes <rom-path>")
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record-audio" => {
+                options.record_audio = Some(PathBuf::from(args.next().expect(USAGE)));
+            }
+            "--record-channels" => options.record_channels = true,
+            "--bus-conflicts" => bus_conflicts = true,
+            "--save-dir" => save_dir = Some(PathBuf::from(args.next().expect(USAGE))),
+            "--frames" => {
+                options.frames = Some(args.next().and_then(|n| n.parse().ok()).expect(USAGE));
+            }
+            _ => path = Some(arg),
+        }
     }
 
-    let path = &args[1];
+    let path = path.expect(USAGE);
     println!("{:?}", path);
 
-    let rom = Rom::load(&mut File::open(&Path::new(path)).unwrap()).unwrap();
+    let mut rom = Rom::load(&mut File::open(&Path::new(&path)).unwrap()).unwrap();
+    rom.bus_conflicts = bus_conflicts;
+    options.save_file = Some(battery::save_path(Path::new(&path), save_dir.as_deref()));
 
-    start(rom);
+    start(rom, options);
 }
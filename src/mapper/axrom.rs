@@ -0,0 +1,79 @@
+use super::{bus_conflict, Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+/// AxROM (mapper 7): a switchable 32 KB PRG bank and single-screen
+/// mirroring, both selected by writing to $8000-$FFFF (xxxM xPPP: nametable
+/// page, PRG bank). CHR is 8 KB of RAM.
+pub struct Axrom {
+    rom: Rom,
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl Axrom {
+    pub fn new(rom: Rom) -> Axrom {
+        Axrom {
+            rom: rom,
+            prg_bank: 0,
+            mirroring: Mirroring::SingleScreenA,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000...0xFFFF => {
+                let offset = self.prg_bank * PRG_BANK_SIZE + (addr as usize - 0x8000);
+                self.rom.prg[offset % self.rom.prg.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            let val = bus_conflict(&self.rom, self.peek(addr), val);
+            self.prg_bank = (val & 0x07) as usize;
+            self.mirroring = if val & 0x10 == 0 {
+                Mirroring::SingleScreenA
+            } else {
+                Mirroring::SingleScreenB
+            };
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[addr as usize]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            self.rom.chr[addr as usize] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_usize(self.prg_bank);
+        self.mirroring.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.prg_bank = state.read_usize()?;
+        self.mirroring = Mirroring::load_state(state)?;
+        Ok(())
+    }
+}
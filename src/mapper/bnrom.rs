@@ -0,0 +1,114 @@
+use super::{bus_conflict, Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+/// Mapper 34, which covers two unrelated boards with a switchable 32 KB PRG
+/// bank:
+///
+/// * BNROM (submapper 2) selects the PRG bank by writing to $8000-$FFFF and
+///   has 8 KB of CHR-RAM.
+/// * NINA-001 (submapper 1) has 8 KB of PRG-RAM and registers in its last
+///   bytes: $7FFD selects the PRG bank, $7FFE and $7FFF select 4 KB CHR
+///   banks at $0000 and $1000.
+///
+/// Without a submapper, NINA-001 is recognized by its CHR-ROM.
+pub struct Bnrom {
+    rom: Rom,
+    nina: bool,
+    prg_bank: usize,
+    chr_banks: [usize; 2],
+}
+
+impl Bnrom {
+    pub fn new(rom: Rom) -> Bnrom {
+        let nina = match rom.header.submapper() {
+            1 => true,
+            2 => false,
+            _ => rom.chr.len() > 0x2000,
+        };
+        Bnrom {
+            rom: rom,
+            nina: nina,
+            prg_bank: 0,
+            chr_banks: [0, 1],
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE];
+        (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.rom.chr.len()
+    }
+}
+
+impl Mapper for Bnrom {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF if self.nina => {
+                self.rom.sram[(addr as usize - 0x6000) % self.rom.sram.len()]
+            }
+            0x8000...0xFFFF => {
+                let offset = self.prg_bank * PRG_BANK_SIZE + (addr as usize - 0x8000);
+                self.rom.prg[offset % self.rom.prg.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF if self.nina => {
+                // The registers don't prevent the write to PRG-RAM
+                let len = self.rom.sram.len();
+                self.rom.sram[(addr as usize - 0x6000) % len] = val;
+                match addr {
+                    0x7FFD => self.prg_bank = (val & 0x01) as usize,
+                    0x7FFE => self.chr_banks[0] = (val & 0x0F) as usize,
+                    0x7FFF => self.chr_banks[1] = (val & 0x0F) as usize,
+                    _ => {}
+                }
+            }
+            0x8000...0xFFFF if !self.nina => {
+                let val = bus_conflict(&self.rom, self.peek(addr), val);
+                self.prg_bank = val as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.header.mirroring()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_usize(self.prg_bank);
+        state.write_usize(self.chr_banks[0]);
+        state.write_usize(self.chr_banks[1]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.prg_bank = state.read_usize()?;
+        self.chr_banks[0] = state.read_usize()?;
+        self.chr_banks[1] = state.read_usize()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use super::{Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+/// Camerica BF909x (mapper 71): a switchable 16 KB PRG bank at $8000,
+/// selected by writing to $C000-$FFFF, and the last bank fixed at $C000.
+///
+/// The BF9097 used by Fire Hawk (submapper 1) also selects single-screen
+/// mirroring with bit 4 of writes to $8000-$9FFF. Other boards have fixed
+/// mirroring, and some games write to $8000 for other reasons, so without
+/// the submapper the mirroring is only controlled by the mapper once a write
+/// to $9000-$9FFF happened, which is where Fire Hawk writes.
+pub struct Camerica {
+    rom: Rom,
+    prg_bank: usize,
+    mirroring: Option<Mirroring>,
+}
+
+impl Camerica {
+    pub fn new(rom: Rom) -> Camerica {
+        Camerica {
+            rom: rom,
+            prg_bank: 0,
+            mirroring: None,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = self.rom.prg.len() / PRG_BANK_SIZE;
+        let bank = if addr < 0xC000 {
+            self.prg_bank % bank_count
+        } else {
+            bank_count - 1
+        };
+        bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+}
+
+impl Mapper for Camerica {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x8000...0x9FFF if self.rom.header.submapper() == 1 || addr >= 0x9000 => {
+                self.mirroring = Some(if val & 0x10 == 0 {
+                    Mirroring::SingleScreenA
+                } else {
+                    Mirroring::SingleScreenB
+                });
+            }
+            0xC000...0xFFFF => self.prg_bank = val as usize,
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[addr as usize]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            self.rom.chr[addr as usize] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+            .unwrap_or_else(|| self.rom.header.mirroring())
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_usize(self.prg_bank);
+        state.write_bool(self.mirroring.is_some());
+        self.mirroring().save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.prg_bank = state.read_usize()?;
+        let controlled = state.read_bool()?;
+        let mirroring = Mirroring::load_state(state)?;
+        self.mirroring = if controlled { Some(mirroring) } else { None };
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use super::{bus_conflict, Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// CNROM (mapper 3): 16 or 32 KB of fixed PRG-ROM and a switchable 8 KB CHR
+/// bank, selected by writing to $8000-$FFFF.
+pub struct Cnrom {
+    rom: Rom,
+    chr_bank: usize,
+}
+
+impl Cnrom {
+    pub fn new(rom: Rom) -> Cnrom {
+        Cnrom {
+            rom: rom,
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        (self.chr_bank * CHR_BANK_SIZE + addr as usize) % self.rom.chr.len()
+    }
+}
+
+impl Mapper for Cnrom {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            // 16 KB of PRG-ROM is mirrored at $C000
+            0x8000...0xFFFF => self.rom.prg[(addr as usize - 0x8000) % self.rom.prg.len()],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            let val = bus_conflict(&self.rom, self.peek(addr), val);
+            self.chr_bank = val as usize;
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _val: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.header.mirroring()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_usize(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.chr_bank = state.read_usize()?;
+        Ok(())
+    }
+}
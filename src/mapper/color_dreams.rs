@@ -0,0 +1,72 @@
+use super::{bus_conflict, Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// Color Dreams (mapper 11): a switchable 32 KB PRG bank and 8 KB CHR bank,
+/// both selected by writing to $8000-$FFFF (CCCC xxPP).
+pub struct ColorDreams {
+    rom: Rom,
+    prg_bank: usize,
+    chr_bank: usize,
+}
+
+impl ColorDreams {
+    pub fn new(rom: Rom) -> ColorDreams {
+        ColorDreams {
+            rom: rom,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for ColorDreams {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000...0xFFFF => {
+                let offset = self.prg_bank * PRG_BANK_SIZE + (addr as usize - 0x8000);
+                self.rom.prg[offset % self.rom.prg.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            let val = bus_conflict(&self.rom, self.peek(addr), val);
+            self.prg_bank = (val & 0x03) as usize;
+            self.chr_bank = (val >> 4) as usize;
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        let offset = self.chr_bank * CHR_BANK_SIZE + addr as usize;
+        self.rom.chr[offset % self.rom.chr.len()]
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _val: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.header.mirroring()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_usize(self.prg_bank);
+        state.write_usize(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.prg_bank = state.read_usize()?;
+        self.chr_bank = state.read_usize()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+mod audio;
+
+use self::audio::Audio;
+use super::{Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// Sunsoft FME-7 and 5B (mapper 69).
+///
+/// The registers are written by selecting one with a command at $8000-$9FFF
+/// and writing its parameter to $A000-$BFFF:
+///
+/// * $0-$7: CHR banks 0-7, 1 KB each
+/// * $8: Bank at $6000 (ERBBBBBB: RAM enable, RAM instead of ROM, bank)
+/// * $9-$B: PRG banks at $8000, $A000 and $C000
+/// * $C: Mirroring
+/// * $D: IRQ control (C------T: counter enable, IRQ enable), also
+///   acknowledges the IRQ
+/// * $E, $F: IRQ counter low and high byte
+///
+/// The last 8 KB PRG bank is fixed at $E000. The 16-bit IRQ counter counts
+/// down every CPU cycle and raises the IRQ when it wraps around from 0.
+///
+/// The 5B adds expansion audio at $C000-$FFFF (see `audio::Audio`).
+pub struct Fme7 {
+    rom: Rom,
+    command: u8,
+    chr_banks: [u8; 8],
+    /// $6000 bank (command 8)
+    low_bank: u8,
+    prg_banks: [u8; 3],
+    mirroring: Mirroring,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq: bool,
+    audio: Audio,
+}
+
+impl Fme7 {
+    pub fn new(rom: Rom) -> Fme7 {
+        Fme7 {
+            rom: rom,
+            command: 0,
+            chr_banks: [0; 8],
+            low_bank: 0,
+            prg_banks: [0; 3],
+            mirroring: Mirroring::Vertical,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq: false,
+            audio: Audio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = self.rom.prg.len() / PRG_BANK_SIZE;
+        let bank = match addr {
+            0x6000...0x7FFF => (self.low_bank & 0x3F) as usize,
+            0x8000...0xDFFF => self.prg_banks[(addr as usize - 0x8000) / PRG_BANK_SIZE] as usize,
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    /// Whether PRG-RAM is mapped at $6000 instead of PRG-ROM
+    fn prg_ram_selected(&self) -> bool {
+        self.low_bank & 0x40 != 0
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.low_bank & 0xC0 == 0xC0
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> usize {
+        let bank = (self.low_bank & 0x3F) as usize;
+        (bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))) % self.rom.sram.len()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.rom.chr.len()
+    }
+
+    fn write_parameter(&mut self, val: u8) {
+        match self.command {
+            0x0...0x7 => self.chr_banks[self.command as usize] = val,
+            0x8 => self.low_bank = val,
+            0x9...0xB => self.prg_banks[self.command as usize - 0x9] = val & 0x3F,
+            0xC => {
+                self.mirroring = match val & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                };
+            }
+            0xD => {
+                self.irq_enabled = val & 0x01 != 0;
+                self.irq_counter_enabled = val & 0x80 != 0;
+                self.irq = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | u16::from(val),
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | u16::from(val) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled() => self.rom.sram[self.prg_ram_offset(addr)],
+            0x6000...0x7FFF if self.prg_ram_selected() => 0,
+            0x6000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled() => {
+                let offset = self.prg_ram_offset(addr);
+                self.rom.sram[offset] = val;
+            }
+            0x8000...0x9FFF => self.command = val & 0x0F,
+            0xA000...0xBFFF => self.write_parameter(val),
+            0xC000...0xDFFF => self.audio.select_register(val),
+            0xE000...0xFFFF => self.audio.write_data(val),
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+
+    fn cpu_cycle(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq = true;
+            }
+        }
+        self.audio.step();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_u8(self.command);
+        state.write_bytes(&self.chr_banks);
+        state.write_u8(self.low_bank);
+        state.write_bytes(&self.prg_banks);
+        self.mirroring.save_state(state);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_counter_enabled);
+        state.write_u16(self.irq_counter);
+        state.write_bool(self.irq);
+        self.audio.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.command = state.read_u8()?;
+        state.read_bytes(&mut self.chr_banks)?;
+        self.low_bank = state.read_u8()?;
+        state.read_bytes(&mut self.prg_banks)?;
+        self.mirroring = Mirroring::load_state(state)?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_counter_enabled = state.read_bool()?;
+        self.irq_counter = state.read_u16()?;
+        self.irq = state.read_bool()?;
+        self.audio.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Fme7;
+    use crate::mapper::Mapper;
+    use crate::rom::Rom;
+
+    fn mapper() -> Fme7 {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x50, 0x40];
+        image.resize(16 + 2 * 0x4000 + 0x2000, 0);
+        Fme7::new(Rom::load(&mut &image[..]).unwrap())
+    }
+
+    fn command(mapper: &mut Fme7, command: u8, val: u8) {
+        mapper.cpu_write(0x8000, command);
+        mapper.cpu_write(0xA000, val);
+    }
+
+    #[test]
+    fn irq_on_counter_wrap() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0xE, 0x02);
+        command(&mut mapper, 0xF, 0x00);
+        command(&mut mapper, 0xD, 0x81);
+        mapper.cpu_cycle();
+        mapper.cpu_cycle();
+        assert!(!mapper.irq());
+        // $0000 -> $FFFF
+        mapper.cpu_cycle();
+        assert!(mapper.irq());
+        command(&mut mapper, 0xD, 0x81);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn counter_wraps_without_irq_when_disabled() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0xE, 0x00);
+        command(&mut mapper, 0xF, 0x00);
+        command(&mut mapper, 0xD, 0x80);
+        mapper.cpu_cycle();
+        assert!(!mapper.irq());
+        assert_eq!(mapper.irq_counter, 0xFFFF);
+        mapper.cpu_cycle();
+        assert_eq!(mapper.irq_counter, 0xFFFE);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Output level of a channel at full volume, relative to the APU's output
+const CHANNEL_SCALE: f32 = 0.12;
+
+/// A tone channel: a square wave with a 12-bit period, in units of 16 CPU
+/// cycles per half period.
+#[derive(Default)]
+struct Tone {
+    period: u16,
+    counter: u16,
+    high: bool,
+}
+
+impl Tone {
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter >= self.period.max(1) {
+            self.counter = 0;
+            self.high = !self.high;
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.period);
+        state.write_u16(self.counter);
+        state.write_bool(self.high);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.period = state.read_u16()?;
+        self.counter = state.read_u16()?;
+        self.high = state.read_bool()?;
+        Ok(())
+    }
+}
+
+/// The Sunsoft 5B's expansion audio, a Yamaha YM2149F (a variant of the
+/// General Instrument AY-3-8910): three square wave channels, a noise
+/// generator and an envelope generator.
+///
+/// The registers are selected through $C000 and written through $E000:
+///
+/// * $00-$05: Tone periods of channels A, B and C (low 8 bits, high 4 bits)
+/// * $06: Noise period (5 bits)
+/// * $07: Mixer (--CBAcba: noise disable, tone disable for each channel)
+/// * $08-$0A: Channel volumes (---EVVVV: use envelope, volume)
+/// * $0B, $0C: Envelope period (low, high)
+/// * $0D: Envelope shape (CAtH: continue, attack, alternate, hold)
+///
+/// The chip runs at half the CPU clock and divides it by 8 for the tone and
+/// noise generators.
+pub struct Audio {
+    register: u8,
+    registers: [u8; 16],
+    tones: [Tone; 3],
+    /// CPU cycles until the next tone and noise clock
+    divider: u8,
+
+    noise_counter: u8,
+    /// 17-bit linear feedback shift register
+    noise_shift: u32,
+
+    envelope_counter: u16,
+    /// Envelope step (0-31)
+    envelope_step: u8,
+    /// The envelope counts down instead of up
+    envelope_falling: bool,
+    envelope_holding: bool,
+    /// Volume levels of the 32 envelope steps (the channel volumes use every
+    /// other one), 1.5 dB apart
+    levels: [f32; 32],
+}
+
+impl Audio {
+    pub fn new() -> Audio {
+        let mut levels = [0.0; 32];
+        for (step, level) in levels.iter_mut().enumerate().skip(1) {
+            *level = 10f32.powf(-1.5 * (31 - step) as f32 / 20.0);
+        }
+        Audio {
+            register: 0,
+            registers: [0; 16],
+            tones: Default::default(),
+            divider: 0,
+            noise_counter: 0,
+            noise_shift: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_falling: true,
+            envelope_holding: false,
+            levels: levels,
+        }
+    }
+
+    pub fn select_register(&mut self, val: u8) {
+        self.register = val;
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        // The upper nibble must be 0, a write protection of sorts
+        if self.register > 0x0F {
+            return;
+        }
+        let reg = self.register as usize;
+        self.registers[reg] = val;
+        match reg {
+            0x00...0x05 => {
+                let channel = reg / 2;
+                self.tones[channel].period = u16::from(self.registers[channel * 2])
+                    | u16::from(self.registers[channel * 2 + 1] & 0x0F) << 8;
+            }
+            // Restarts the envelope
+            0x0D => {
+                self.envelope_counter = 0;
+                self.envelope_holding = false;
+                self.envelope_falling = val & 0x04 == 0;
+                self.envelope_step = if self.envelope_falling { 31 } else { 0 };
+            }
+            _ => {}
+        }
+    }
+
+    fn envelope_period(&self) -> u16 {
+        u16::from(self.registers[0x0B]) | u16::from(self.registers[0x0C]) << 8
+    }
+
+    fn clock_noise(&mut self) {
+        self.noise_counter += 1;
+        // Clocked at half the rate of the tone generators
+        if self.noise_counter >= (self.registers[0x06] & 0x1F).max(1) * 2 {
+            self.noise_counter = 0;
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 0x01;
+            self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        self.envelope_counter += 1;
+        if self.envelope_counter < self.envelope_period().max(1) {
+            return;
+        }
+        self.envelope_counter = 0;
+        if self.envelope_holding {
+            return;
+        }
+        let at_end = if self.envelope_falling {
+            self.envelope_step == 0
+        } else {
+            self.envelope_step == 31
+        };
+        if !at_end {
+            if self.envelope_falling {
+                self.envelope_step -= 1;
+            } else {
+                self.envelope_step += 1;
+            }
+            return;
+        }
+        let shape = self.registers[0x0D];
+        let continuing = shape & 0x08 != 0;
+        let alternate = shape & 0x02 != 0;
+        let hold = shape & 0x01 != 0;
+        if !continuing {
+            // Shapes 0-7 drop to 0 and stay there
+            self.envelope_step = 0;
+            self.envelope_holding = true;
+        } else if hold {
+            if alternate {
+                self.envelope_step = 31 - self.envelope_step;
+            }
+            self.envelope_holding = true;
+        } else if alternate {
+            self.envelope_falling = !self.envelope_falling;
+        } else {
+            self.envelope_step = if self.envelope_falling { 31 } else { 0 };
+        }
+    }
+
+    /// Advances the chip by one CPU cycle.
+    pub fn step(&mut self) {
+        self.divider += 1;
+        if self.divider < 16 {
+            return;
+        }
+        self.divider = 0;
+        for tone in self.tones.iter_mut() {
+            tone.clock();
+        }
+        self.clock_noise();
+        self.clock_envelope();
+    }
+
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[0x07];
+        let noise = self.noise_shift & 0x01 != 0;
+        let mut output = 0.0;
+        for (channel, tone) in self.tones.iter().enumerate() {
+            let tone_on = tone.high || mixer & (0x01 << channel) != 0;
+            let noise_on = noise || mixer & (0x08 << channel) != 0;
+            if !(tone_on && noise_on) {
+                continue;
+            }
+            let volume = self.registers[0x08 + channel];
+            let step = if volume & 0x10 != 0 {
+                self.envelope_step
+            } else if volume & 0x0F == 0 {
+                0
+            } else {
+                (volume & 0x0F) * 2 + 1
+            };
+            output += self.levels[step as usize];
+        }
+        output * CHANNEL_SCALE
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_bytes(&self.registers);
+        for tone in self.tones.iter() {
+            tone.save_state(state);
+        }
+        state.write_u8(self.divider);
+        state.write_u8(self.noise_counter);
+        state.write_u32(self.noise_shift);
+        state.write_u16(self.envelope_counter);
+        state.write_u8(self.envelope_step);
+        state.write_bool(self.envelope_falling);
+        state.write_bool(self.envelope_holding);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        state.read_bytes(&mut self.registers)?;
+        for tone in self.tones.iter_mut() {
+            tone.load_state(state)?;
+        }
+        self.divider = state.read_u8()?;
+        self.noise_counter = state.read_u8()?;
+        self.noise_shift = state.read_u32()?;
+        self.envelope_counter = state.read_u16()?;
+        self.envelope_step = state.read_u8()?;
+        self.envelope_falling = state.read_bool()?;
+        self.envelope_holding = state.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+use super::{Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Size of a PRG-ROM bank
+const PRG_BANK_SIZE: usize = 0x4000;
+/// Size of a CHR bank in 4 KB mode
+const CHR_BANK_SIZE: usize = 0x1000;
+/// Size of a PRG-RAM bank
+const PRG_RAM_BANK_SIZE: usize = 0x2000;
+
+/// MMC1 (mapper 1), used by SxROM boards.
+///
+/// The registers are loaded serially: each write to $8000-$FFFF shifts bit 0
+/// into a 5-bit shift register, and the fifth write copies it to the
+/// register selected by bits 13 and 14 of its address. Writing a value with
+/// bit 7 set resets the shift register instead.
+///
+/// * $8000-$9FFF: Control (CPPMM: CHR mode, PRG mode, mirroring)
+/// * $A000-$BFFF: CHR bank 0
+/// * $C000-$DFFF: CHR bank 1
+/// * $E000-$FFFF: PRG bank (RPPPP: PRG-RAM disable, PRG bank)
+///
+/// Boards with 512 KB of PRG-ROM (SUROM, SXROM) use bit 4 of the CHR bank
+/// registers to select a 256 KB half of the PRG-ROM, and boards with 32 KB
+/// of PRG-RAM (SXROM) use bits 2-3 to select an 8 KB PRG-RAM bank.
+pub struct Mmc1 {
+    rom: Rom,
+    shift: u8,
+    /// Number of bits shifted in so far
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    /// Set by a write to the serial port until the next CPU cycle. The MMC1
+    /// ignores writes on consecutive cycles, such as the two writes of a
+    /// read-modify-write instruction.
+    wrote: bool,
+}
+
+impl Mmc1 {
+    pub fn new(rom: Rom) -> Mmc1 {
+        Mmc1 {
+            rom: rom,
+            shift: 0,
+            shift_count: 0,
+            // PRG mode 3 (last bank fixed) on power-up
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            wrote: false,
+        }
+    }
+
+    fn write_serial(&mut self, addr: u16, val: u8) {
+        if self.wrote {
+            return;
+        }
+        self.wrote = true;
+
+        if val & 0x80 != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+
+        self.shift |= (val & 1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count == 5 {
+            let val = self.shift;
+            match addr {
+                0x8000...0x9FFF => self.control = val,
+                0xA000...0xBFFF => self.chr_bank_0 = val,
+                0xC000...0xDFFF => self.chr_bank_1 = val,
+                _ => self.prg_bank = val,
+            }
+            self.shift = 0;
+            self.shift_count = 0;
+        }
+    }
+
+    /// Offset of the 256 KB PRG-ROM half selected on 512 KB boards
+    fn prg_outer_bank(&self) -> usize {
+        if self.rom.prg.len() > 0x40000 {
+            (self.chr_bank_0 as usize & 0x10) << 14
+        } else {
+            0
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = (self.rom.prg.len() / PRG_BANK_SIZE).min(16);
+        let bank = self.prg_bank as usize & 0x0F;
+        let bank = match ((self.control >> 2) & 0x03, addr) {
+            // 32 KB mode, ignoring the low bit of the bank number
+            (0, _) | (1, _) => (bank & !1) | (addr as usize >> 14 & 1),
+            // First bank fixed at $8000
+            (2, 0x8000...0xBFFF) => 0,
+            (2, _) => bank,
+            // Last bank fixed at $C000
+            (_, 0x8000...0xBFFF) => bank,
+            (_, _) => bank_count - 1,
+        };
+        let offset = self.prg_outer_bank() + (bank % bank_count) * PRG_BANK_SIZE;
+        (offset + (addr as usize & (PRG_BANK_SIZE - 1))) % self.rom.prg.len()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = if self.control & 0x10 == 0 {
+            // 8 KB mode, ignoring the low bit of the bank number
+            (self.chr_bank_0 as usize & !1) | (addr as usize >> 12)
+        } else if addr < 0x1000 {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        };
+        (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.rom.chr.len()
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> usize {
+        let bank = (self.chr_bank_0 as usize >> 2) & 0x03;
+        (bank * PRG_RAM_BANK_SIZE + (addr as usize - 0x6000)) % self.rom.sram.len()
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled() => self.rom.sram[self.prg_ram_offset(addr)],
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled() => {
+                let offset = self.prg_ram_offset(addr);
+                self.rom.sram[offset] = val;
+            }
+            0x8000...0xFFFF => self.write_serial(addr, val),
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.wrote = false;
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_u8(self.shift);
+        state.write_u8(self.shift_count);
+        state.write_u8(self.control);
+        state.write_u8(self.chr_bank_0);
+        state.write_u8(self.chr_bank_1);
+        state.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.shift = state.read_u8()?;
+        self.shift_count = state.read_u8()?;
+        self.control = state.read_u8()?;
+        self.chr_bank_0 = state.read_u8()?;
+        self.chr_bank_1 = state.read_u8()?;
+        self.prg_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Mmc1;
+    use crate::mapper::Mapper;
+    use crate::rom::Rom;
+
+    /// A ROM with 16 KB PRG banks and 4 KB CHR banks filled with their number
+    fn mapper(prg_banks: u8) -> Mmc1 {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, 4, 0x10, 0x00];
+        image.resize(16, 0);
+        for bank in 0..prg_banks {
+            image.extend(vec![bank; 0x4000]);
+        }
+        for bank in 0..8 {
+            image.extend(vec![bank; 0x1000]);
+        }
+        Mmc1::new(Rom::load(&mut &image[..]).unwrap())
+    }
+
+    /// Loads a register through the serial port, one bit per CPU cycle.
+    fn write(mapper: &mut Mmc1, addr: u16, val: u8) {
+        for i in 0..5 {
+            mapper.cpu_write(addr, val >> i & 1);
+            mapper.cpu_cycle();
+        }
+    }
+
+    #[test]
+    fn last_bank_fixed_on_power_up() {
+        let mut mapper = mapper(8);
+        assert_eq!(mapper.peek(0x8000), 0);
+        assert_eq!(mapper.peek(0xC000), 7);
+        write(&mut mapper, 0xE000, 3);
+        assert_eq!(mapper.peek(0x8000), 3);
+        assert_eq!(mapper.peek(0xFFFF), 7);
+    }
+
+    #[test]
+    fn prg_modes() {
+        let mut mapper = mapper(8);
+        write(&mut mapper, 0xE000, 5);
+        // 32 KB mode ignores the low bit of the bank number
+        write(&mut mapper, 0x8000, 0x00);
+        assert_eq!(mapper.peek(0x8000), 4);
+        assert_eq!(mapper.peek(0xC000), 5);
+        // First bank fixed at $8000
+        write(&mut mapper, 0x8000, 0x08);
+        assert_eq!(mapper.peek(0x8000), 0);
+        assert_eq!(mapper.peek(0xC000), 5);
+    }
+
+    #[test]
+    fn chr_modes() {
+        let mut mapper = mapper(8);
+        write(&mut mapper, 0xA000, 3);
+        write(&mut mapper, 0xC000, 6);
+        // 8 KB mode ignores the low bit of the bank number and bank 1
+        assert_eq!(mapper.ppu_peek(0x0000), 2);
+        assert_eq!(mapper.ppu_peek(0x1000), 3);
+        write(&mut mapper, 0x8000, 0x1C);
+        assert_eq!(mapper.ppu_peek(0x0000), 3);
+        assert_eq!(mapper.ppu_peek(0x1000), 6);
+    }
+
+    #[test]
+    fn ignores_writes_on_consecutive_cycles() {
+        let mut mapper = mapper(8);
+        for _ in 0..5 {
+            // The second write of a read-modify-write instruction is dropped
+            mapper.cpu_write(0xE000, 1);
+            mapper.cpu_write(0xE000, 0);
+            mapper.cpu_cycle();
+        }
+        // Bank 15, wrapped around the 8 banks
+        assert_eq!(mapper.peek(0x8000), 7);
+    }
+
+    #[test]
+    fn reset_fixes_last_bank() {
+        let mut mapper = mapper(8);
+        write(&mut mapper, 0x8000, 0x00);
+        write(&mut mapper, 0xE000, 2);
+        mapper.cpu_write(0x8000, 0x80);
+        assert_eq!(mapper.peek(0x8000), 2);
+        assert_eq!(mapper.peek(0xC000), 7);
+    }
+
+    #[test]
+    fn outer_prg_bank_on_512k_boards() {
+        let mut mapper = mapper(32);
+        assert_eq!(mapper.peek(0xC000), 15);
+        write(&mut mapper, 0xA000, 0x10);
+        write(&mut mapper, 0xE000, 1);
+        assert_eq!(mapper.peek(0x8000), 17);
+        assert_eq!(mapper.peek(0xC000), 31);
+    }
+}
@@ -0,0 +1,246 @@
+use super::{Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const CHR_BANK_SIZE: usize = 0x1000;
+
+/// MMC2 (mapper 9) and MMC4 (mapper 10).
+///
+/// * $A000-$AFFF: PRG bank at $8000 (MMC2: 8 KB, MMC4: 16 KB)
+/// * $B000-$BFFF: CHR bank at $0000 when latch 0 is $FD
+/// * $C000-$CFFF: CHR bank at $0000 when latch 0 is $FE
+/// * $D000-$DFFF: CHR bank at $1000 when latch 1 is $FD
+/// * $E000-$EFFF: CHR bank at $1000 when latch 1 is $FE
+/// * $F000-$FFFF: Mirroring (0: vertical; 1: horizontal)
+///
+/// The rest of the PRG-ROM is fixed to the last banks. The MMC4 also has 8 KB
+/// of PRG-RAM at $6000.
+///
+/// Each 4 KB pattern table has a latch selecting one of its two CHR banks,
+/// which flips after the PPU fetches the second plane of tile $FD or $FE
+/// from it:
+///
+/// * Latch 0: $0FD8 sets $FD, $0FE8 sets $FE (MMC4: $0FD8-$0FDF and
+///   $0FE8-$0FEF)
+/// * Latch 1: $1FD8-$1FDF sets $FD, $1FE8-$1FEF sets $FE
+///
+/// Games put these tiles at the edges of graphics which need a different
+/// bank, so that the switch happens in the middle of a scanline.
+pub struct Mmc2 {
+    rom: Rom,
+    mmc4: bool,
+    prg_bank: u8,
+    /// $B000-$E000
+    chr_banks: [u8; 4],
+    /// Whether tile $FE (rather than $FD) was fetched last from each pattern
+    /// table
+    latches: [bool; 2],
+    mirroring: Mirroring,
+}
+
+impl Mmc2 {
+    pub fn new(rom: Rom) -> Mmc2 {
+        let mmc4 = rom.header.mapper() == 10;
+        Mmc2 {
+            rom: rom,
+            mmc4: mmc4,
+            prg_bank: 0,
+            chr_banks: [0; 4],
+            latches: [true; 2],
+            mirroring: Mirroring::Vertical,
+        }
+    }
+
+    fn prg_bank_size(&self) -> usize {
+        if self.mmc4 {
+            0x4000
+        } else {
+            0x2000
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_size = self.prg_bank_size();
+        let bank_count = self.rom.prg.len() / bank_size;
+        let offset = addr as usize - 0x8000;
+        if offset < bank_size {
+            (self.prg_bank as usize % bank_count) * bank_size + offset
+        } else {
+            // The fixed banks are the last ones, up to $FFFF
+            self.rom.prg.len() - (0x8000 - offset)
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let table = addr as usize / CHR_BANK_SIZE;
+        let bank = self.chr_banks[table * 2 + self.latches[table] as usize] as usize;
+        (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.rom.chr.len()
+    }
+
+    fn update_latches(&mut self, addr: u16) {
+        match addr {
+            0x0FD8 => self.latches[0] = false,
+            0x0FE8 => self.latches[0] = true,
+            0x0FD9...0x0FDF if self.mmc4 => self.latches[0] = false,
+            0x0FE9...0x0FEF if self.mmc4 => self.latches[0] = true,
+            0x1FD8...0x1FDF => self.latches[1] = false,
+            0x1FE8...0x1FEF => self.latches[1] = true,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF if self.mmc4 => {
+                self.rom.sram[(addr as usize - 0x6000) % self.rom.sram.len()]
+            }
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF if self.mmc4 => {
+                let len = self.rom.sram.len();
+                self.rom.sram[(addr as usize - 0x6000) % len] = val;
+            }
+            0xA000...0xAFFF => self.prg_bank = val & 0x0F,
+            0xB000...0xEFFF => {
+                self.chr_banks[(addr as usize - 0xB000) / 0x1000] = val & 0x1F;
+            }
+            0xF000...0xFFFF => {
+                self.mirroring = if val & 0x01 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        // The fetch itself still uses the old bank
+        let val = self.ppu_peek(addr);
+        self.update_latches(addr);
+        val
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_u8(self.prg_bank);
+        state.write_bytes(&self.chr_banks);
+        state.write_bool(self.latches[0]);
+        state.write_bool(self.latches[1]);
+        self.mirroring.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.prg_bank = state.read_u8()?;
+        state.read_bytes(&mut self.chr_banks)?;
+        self.latches[0] = state.read_bool()?;
+        self.latches[1] = state.read_bool()?;
+        self.mirroring = Mirroring::load_state(state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::Mmc2;
+    use crate::mapper::Mapper;
+    use crate::ppu::{Ppu, SCREEN_WIDTH};
+    use crate::rom::Rom;
+
+    /// Builds an MMC2 ROM whose 4 KB CHR bank 1 is filled with color 3 and
+    /// the others with color 0.
+    fn rom() -> Rom {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 8, 16, 0x90, 0x00];
+        image.resize(16, 0);
+        image.resize(16 + 8 * 0x4000, 0);
+        for bank in 0..32 {
+            let val = if bank == 1 { 0xFF } else { 0x00 };
+            image.extend(vec![val; 0x1000]);
+        }
+        Rom::load(&mut &image[..]).unwrap()
+    }
+
+    #[test]
+    fn latch_switching_mid_scanline() {
+        let mut mmc2 = Mmc2::new(rom());
+        // Latch 1: $FD selects bank 1, $FE bank 2
+        mmc2.cpu_write(0xD000, 1);
+        mmc2.cpu_write(0xE000, 2);
+        let mapper: Rc<RefCell<Box<Mapper>>> = Rc::new(RefCell::new(Box::new(mmc2)));
+        let mut ppu = Ppu::new(mapper);
+
+        // Every row of the nametable has tile $FD at column 10 and $FE at
+        // column 20, the rest is tile 0
+        ppu.write_register(0x2006, 0x20);
+        ppu.write_register(0x2006, 0x00);
+        for _ in 0..30 {
+            for column in 0..32 {
+                let tile = match column {
+                    10 => 0xFD,
+                    20 => 0xFE,
+                    _ => 0x00,
+                };
+                ppu.write_register(0x2007, tile);
+            }
+        }
+        // Black background, white color 3
+        ppu.write_register(0x2006, 0x3F);
+        ppu.write_register(0x2006, 0x00);
+        for &color in [0x0F, 0x0F, 0x0F, 0x30].iter() {
+            ppu.write_register(0x2007, color);
+        }
+        ppu.write_register(0x2006, 0x00);
+        ppu.write_register(0x2006, 0x00);
+        // Background from $1000, shown in the leftmost 8 pixels too
+        ppu.write_register(0x2000, 0x10);
+        ppu.write_register(0x2001, 0x0A);
+
+        for _ in 0..2 {
+            while !ppu.take_frame_complete() {
+                ppu.step();
+            }
+        }
+
+        // The second plane of $FD flips the latch, so tile $FD itself is
+        // still drawn from bank 2, and the following tiles up to and
+        // including $FE from bank 1
+        for &y in [0, 100, 239].iter() {
+            for x in 0..SCREEN_WIDTH {
+                let white = ppu.screen[(y * SCREEN_WIDTH + x) * 3] != 0;
+                assert_eq!(white, (88..168).contains(&x), "pixel ({}, {})", x, y);
+            }
+        }
+    }
+}
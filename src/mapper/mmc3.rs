@@ -0,0 +1,353 @@
+use super::{Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Size of a PRG bank
+const PRG_BANK_SIZE: usize = 0x2000;
+/// Size of a CHR bank
+const CHR_BANK_SIZE: usize = 0x0400;
+/// CPU cycles PPU A12 has to stay low before a rising edge clocks the
+/// scanline counter. This filters out the edges between the individual
+/// sprite pattern fetches.
+const A12_FILTER_CYCLES: u8 = 3;
+
+/// MMC3 (mapper 4), used by TxROM boards.
+///
+/// * $8000-$9FFE (even): Bank select (CPxx xRRR: CHR inversion, PRG mode,
+///   bank register to update)
+/// * $8001-$9FFF (odd): Bank data
+/// * $A000-$BFFE (even): Mirroring
+/// * $A001-$BFFF (odd): PRG-RAM protect (RWxx xxxx: enable, write protect)
+/// * $C000-$DFFE (even): IRQ latch
+/// * $C001-$DFFF (odd): IRQ reload
+/// * $E000-$FFFE (even): IRQ disable and acknowledge
+/// * $E001-$FFFF (odd): IRQ enable
+///
+/// The scanline counter is clocked by rising edges of PPU A12, which happen
+/// once per scanline when the background and sprites use different pattern
+/// tables.
+pub struct Mmc3 {
+    rom: Rom,
+    bank_select: u8,
+    /// Bank registers R0-R7
+    banks: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    prg_ram_write_protect: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq: bool,
+
+    /// Last level of PPU A12
+    a12: bool,
+    /// CPU cycles since PPU A12 went low
+    a12_low_cycles: u8,
+}
+
+impl Mmc3 {
+    pub fn new(rom: Rom) -> Mmc3 {
+        let mirroring = rom.header.mirroring();
+        Mmc3 {
+            rom: rom,
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: mirroring,
+            prg_ram_enabled: true,
+            prg_ram_write_protect: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq: false,
+            a12: false,
+            a12_low_cycles: 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = self.rom.prg.len() / PRG_BANK_SIZE;
+        let second_last = bank_count - 2;
+        let prg_mode = self.bank_select & 0x40 != 0;
+        let bank = match (addr, prg_mode) {
+            (0x8000...0x9FFF, false) | (0xC000...0xDFFF, true) => self.banks[6] as usize,
+            (0x8000...0x9FFF, true) | (0xC000...0xDFFF, false) => second_last,
+            (0xA000...0xBFFF, _) => self.banks[7] as usize,
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        // CHR inversion swaps the 2 KB and 1 KB halves
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        let bank = match addr {
+            0x0000...0x07FF => (self.banks[0] & 0xFE) as usize | (addr as usize >> 10 & 1),
+            0x0800...0x0FFF => (self.banks[1] & 0xFE) as usize | (addr as usize >> 10 & 1),
+            _ => self.banks[2 + (addr as usize - 0x1000) / CHR_BANK_SIZE] as usize,
+        };
+        (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.rom.chr.len()
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq = true;
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000...0x9FFF, true) => self.bank_select = val,
+            (0x8000...0x9FFF, false) => self.banks[(self.bank_select & 0x07) as usize] = val,
+            (0xA000...0xBFFF, true) => {
+                if self.mirroring != Mirroring::FourScreen {
+                    self.mirroring = if val & 1 == 0 {
+                        Mirroring::Vertical
+                    } else {
+                        Mirroring::Horizontal
+                    };
+                }
+            }
+            (0xA000...0xBFFF, false) => {
+                self.prg_ram_enabled = val & 0x80 != 0;
+                self.prg_ram_write_protect = val & 0x40 != 0;
+            }
+            (0xC000...0xDFFF, true) => self.irq_latch = val,
+            (0xC000...0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                self.irq_enabled = false;
+                self.irq = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled => {
+                self.rom.sram[(addr as usize - 0x6000) % self.rom.sram.len()]
+            }
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled && !self.prg_ram_write_protect => {
+                let len = self.rom.sram.len();
+                self.rom.sram[(addr as usize - 0x6000) % len] = val;
+            }
+            0x8000...0xFFFF => self.write_register(addr, val),
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+
+    fn cpu_cycle(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn ppu_a12(&mut self, high: bool) {
+        if high && !self.a12 && self.a12_low_cycles >= A12_FILTER_CYCLES {
+            self.clock_irq_counter();
+        }
+        if !high && self.a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = high;
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_u8(self.bank_select);
+        for &bank in self.banks.iter() {
+            state.write_u8(bank);
+        }
+        self.mirroring.save_state(state);
+        state.write_bool(self.prg_ram_enabled);
+        state.write_bool(self.prg_ram_write_protect);
+        state.write_u8(self.irq_latch);
+        state.write_u8(self.irq_counter);
+        state.write_bool(self.irq_reload);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq);
+        state.write_bool(self.a12);
+        state.write_u8(self.a12_low_cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.bank_select = state.read_u8()?;
+        for bank in self.banks.iter_mut() {
+            *bank = state.read_u8()?;
+        }
+        self.mirroring = Mirroring::load_state(state)?;
+        self.prg_ram_enabled = state.read_bool()?;
+        self.prg_ram_write_protect = state.read_bool()?;
+        self.irq_latch = state.read_u8()?;
+        self.irq_counter = state.read_u8()?;
+        self.irq_reload = state.read_bool()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq = state.read_bool()?;
+        self.a12 = state.read_bool()?;
+        self.a12_low_cycles = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::Mmc3;
+    use crate::mapper::Mapper;
+    use crate::ppu::Ppu;
+    use crate::rom::Rom;
+    use crate::state::{StateReader, StateWriter};
+
+    const PPU_DOTS_PER_CPU_CYCLE: usize = 3;
+
+    fn rom() -> Rom {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x40, 0x00];
+        image.resize(16 + 2 * 0x4000 + 0x2000, 0);
+        Rom::load(&mut &image[..]).unwrap()
+    }
+
+    /// Runs the PPU for a frame with the background at $0000 and sprites at
+    /// $1000, starting from vblank, and returns the scanline and dot of each
+    /// IRQ, acknowledging it.
+    fn irq_positions(latch: u8) -> Vec<(usize, usize)> {
+        let mapper: Rc<RefCell<Box<Mapper>>> = Rc::new(RefCell::new(Box::new(Mmc3::new(rom()))));
+        let mut ppu = Ppu::new(mapper.clone());
+        ppu.write_register(0x2000, 0x08);
+        ppu.write_register(0x2001, 0x18);
+
+        let mut dots = 0;
+        let mut step = |ppu: &mut Ppu| {
+            ppu.step();
+            dots += 1;
+            if dots % PPU_DOTS_PER_CPU_CYCLE == 0 {
+                mapper.borrow_mut().cpu_cycle();
+            }
+        };
+        while !ppu.take_frame_complete() {
+            step(&mut ppu);
+        }
+        {
+            let mut mapper = mapper.borrow_mut();
+            mapper.cpu_write(0xC000, latch);
+            mapper.cpu_write(0xC001, 0);
+            mapper.cpu_write(0xE000, 0);
+            mapper.cpu_write(0xE001, 0);
+        }
+        let mut positions = Vec::new();
+        while !ppu.take_frame_complete() {
+            step(&mut ppu);
+            let mut mapper = mapper.borrow_mut();
+            if mapper.irq() {
+                positions.push(ppu.position());
+                mapper.cpu_write(0xE000, 0);
+                mapper.cpu_write(0xE001, 0);
+            }
+        }
+        positions
+    }
+
+    #[test]
+    fn irq_counter_clocks_at_sprite_fetches() {
+        // With a latch of 0, every clock of the counter raises the IRQ. It is
+        // clocked by the first sprite pattern fetch of each rendered line,
+        // not by the palette reads while drawing.
+        let expected: Vec<_> = (261..262).chain(0..240).map(|line| (line, 261)).collect();
+        assert_eq!(irq_positions(0), expected);
+    }
+
+    #[test]
+    fn irq_scanline() {
+        // The pre-render line reloads the counter, which reaches 0 on line 9
+        assert_eq!(irq_positions(10)[0], (9, 261));
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut mapper = Mmc3::new(rom());
+        for &(addr, val) in [
+            (0x8000, 0x46),
+            (0x8001, 0x01),
+            (0x8000, 0x02),
+            (0x8001, 0x05),
+            (0xA000, 0x01),
+            (0xA001, 0x80),
+            (0x6123, 0x42),
+            (0xC000, 0x10),
+            (0xC001, 0x00),
+            (0xE001, 0x00),
+        ]
+        .iter()
+        {
+            mapper.cpu_write(addr, val);
+        }
+        for _ in 0..3 {
+            mapper.ppu_a12(true);
+            for _ in 0..4 {
+                mapper.cpu_cycle();
+            }
+            mapper.ppu_a12(false);
+        }
+
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        let saved = state.into_bytes();
+
+        let mut restored = Mmc3::new(rom());
+        restored.load_state(&mut StateReader::new(&saved)).unwrap();
+        let mut state = StateWriter::new();
+        restored.save_state(&mut state);
+        assert_eq!(state.into_bytes(), saved);
+    }
+}
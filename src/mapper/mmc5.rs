@@ -0,0 +1,763 @@
+mod audio;
+
+use self::audio::Audio;
+use super::{Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Size of the smallest PRG bank
+const PRG_BANK_SIZE: usize = 0x2000;
+/// Size of a CHR bank for extended attributes and the vertical split
+const CHR_4K_BANK_SIZE: usize = 0x1000;
+const EXRAM_SIZE: usize = 0x400;
+const CIRAM_SIZE: usize = 0x800;
+/// CPU cycles without a PPU read after which the MMC5 considers rendering to
+/// have stopped
+const IDLE_CYCLES: u8 = 3;
+
+/// Number of PPU reads on a rendering scanline, counted from the first
+/// background fetch of dot 1: 32 background tiles, 8 sprites, 2 background
+/// tiles of the next line and two unused nametable fetches, at 4 reads each
+/// (the sprite fetches start with two garbage nametable reads).
+const SPRITE_FETCHES_START: usize = 32 * 4;
+const SPRITE_FETCHES_END: usize = SPRITE_FETCHES_START + 8 * 4;
+const NEXT_LINE_FETCHES_END: usize = SPRITE_FETCHES_END + 2 * 4;
+
+/// What the PPU is fetching, as deduced from the number of reads since the
+/// start of the scanline
+#[derive(Copy, Clone, PartialEq)]
+enum Fetch {
+    /// Background tile `column` (0-33) of the current or the next scanline,
+    /// and which of its four reads (nametable, attribute, pattern low and
+    /// high)
+    Background {
+        column: usize,
+        next_line: bool,
+        step: usize,
+    },
+    Sprite,
+    /// Not rendering: a CPU access through $2007
+    Other,
+}
+
+/// MMC5 (mapper 5), used by ExROM boards.
+///
+/// * $5000-$5015: Expansion audio (see `audio::Audio`)
+/// * $5100: PRG mode (32 KB, 16 KB + 16 KB, 16 KB + 8 KB + 8 KB or 4 x 8 KB)
+/// * $5101: CHR mode (8 KB, 4 KB, 2 KB or 1 KB banks)
+/// * $5102, $5103: PRG-RAM protect, writes are enabled by $02 and $01
+/// * $5104: ExRAM mode (extra nametable, extended attributes, RAM,
+///   read-only RAM)
+/// * $5105: Nametable mapping (DDCCBBAA: CIRAM page 0 or 1, ExRAM or fill
+///   mode for each nametable)
+/// * $5106, $5107: Fill mode tile and attribute
+/// * $5113: PRG-RAM bank at $6000
+/// * $5114-$5117: PRG banks (RBBBBBBB: ROM, bank), depending on the mode
+/// * $5120-$5127: CHR banks for sprites
+/// * $5128-$512B: CHR banks for the background
+/// * $5130: Upper CHR bank bits, latched by writes to the bank registers
+/// * $5200: Vertical split (ES-TTTTT: enable, right side, tile count)
+/// * $5201: Vertical split scroll
+/// * $5202: Vertical split CHR bank (4 KB)
+/// * $5203: Scanline IRQ compare value
+/// * $5204: IRQ enable (write), IRQ pending and in-frame flags (read)
+/// * $5205, $5206: Unsigned 8x8 multiplier
+/// * $5C00-$5FFF: 1 KB of ExRAM
+///
+/// The MMC5 keeps track of the PPU by watching its bus: three reads of the
+/// same nametable address in a row (the unused fetches at the end of a line,
+/// then the first fetch of the next) mark the start of a scanline, and the
+/// number of reads since tells background and sprite fetches apart. With
+/// 8x16 sprites, the sprite and background fetches use separate CHR banks.
+/// It also snoops PPUCTRL for the sprite size.
+pub struct Mmc5 {
+    rom: Rom,
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    /// $5113-$5117
+    prg_banks: [u8; 5],
+    /// $5120-$5127, including the upper bits
+    sprite_chr_banks: [u16; 8],
+    /// $5128-$512B, including the upper bits
+    background_chr_banks: [u16; 4],
+    chr_upper_bits: u8,
+    /// Whether $5128-$512B were written to after $5120-$5127. Outside of
+    /// 8x16 sprite rendering, the last set written to is used.
+    background_chr_last: bool,
+    sprites_8x16: bool,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline: u8,
+
+    multiplicand: u8,
+    multiplier: u8,
+
+    exram: Vec<u8>,
+    /// The console's nametable VRAM, which the MMC5 maps itself
+    ciram: Vec<u8>,
+
+    /// Last address the PPU read and how many times in a row it did
+    last_ppu_addr: u16,
+    ppu_addr_matches: u8,
+    /// PPU reads since the start of the scanline
+    ppu_reads: usize,
+    /// CPU cycles since the last PPU read
+    idle_cycles: u8,
+    /// ExRAM byte of the background tile being fetched, in extended
+    /// attribute mode
+    tile_exram: u8,
+    /// Whether the background tile being fetched is in the split region
+    tile_in_split: bool,
+
+    audio: Audio,
+}
+
+impl Mmc5 {
+    pub fn new(rom: Rom) -> Mmc5 {
+        Mmc5 {
+            rom: rom,
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            sprite_chr_banks: [0; 8],
+            background_chr_banks: [0; 4],
+            chr_upper_bits: 0,
+            background_chr_last: false,
+            sprites_8x16: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            exram: vec![0; EXRAM_SIZE],
+            ciram: vec![0; CIRAM_SIZE],
+            last_ppu_addr: 0,
+            ppu_addr_matches: 0,
+            ppu_reads: 0,
+            idle_cycles: 0,
+            tile_exram: 0,
+            tile_in_split: false,
+            audio: Audio::new(),
+        }
+    }
+
+    /// Returns the bank register index and bank size for a PRG address
+    /// ($8000-$FFFF) in the current mode.
+    fn prg_bank(&self, addr: u16) -> (usize, usize) {
+        let (register, size) = match (self.prg_mode, addr) {
+            (0, _) => (4, 0x8000),
+            (1, 0x8000...0xBFFF) | (2, 0x8000...0xBFFF) => (2, 0x4000),
+            (1, _) => (4, 0x4000),
+            (2, 0xC000...0xDFFF) => (3, 0x2000),
+            (2, _) => (4, 0x2000),
+            (_, _) => (1 + (addr as usize - 0x8000) / PRG_BANK_SIZE, 0x2000),
+        };
+        (register, size)
+    }
+
+    /// Returns the offset of a PRG address into either PRG-ROM (true) or
+    /// PRG-RAM (false).
+    fn prg_offset(&self, addr: u16) -> (bool, usize) {
+        if addr < 0x8000 {
+            let bank = (self.prg_banks[0] & 0x07) as usize;
+            let offset = bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1));
+            return (false, offset % self.rom.sram.len());
+        }
+        let (register, size) = self.prg_bank(addr);
+        let bank = self.prg_banks[register];
+        // $5117 always selects ROM
+        let rom = register == 4 || bank & 0x80 != 0;
+        let bank = if rom { bank & 0x7F } else { bank & 0x07 };
+        let offset = ((bank as usize * PRG_BANK_SIZE) & !(size - 1)) + (addr as usize & (size - 1));
+        if rom {
+            (true, offset % self.rom.prg.len())
+        } else {
+            (false, offset % self.rom.sram.len())
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0x02, 0x01]
+    }
+
+    fn chr_offset(&self, addr: u16, fetch: Fetch) -> usize {
+        let background = match fetch {
+            Fetch::Background { .. } if self.sprites_8x16 => true,
+            Fetch::Sprite if self.sprites_8x16 => false,
+            _ => self.background_chr_last,
+        };
+        let addr = addr as usize;
+        let size = 0x2000 >> self.chr_mode;
+        let offset = if !background {
+            // Sprite banks: $5127 (8 KB), $5123 and $5127 (4 KB), the odd
+            // registers (2 KB) or all of them (1 KB)
+            let slots = 1 << self.chr_mode;
+            let register = (addr / size + 1) * (8 / slots) - 1;
+            self.sprite_chr_banks[register] as usize * size + addr % size
+        } else if self.chr_mode == 0 {
+            self.background_chr_banks[3] as usize * size + addr
+        } else {
+            // Background banks: the same layout in 4 KB, used for both
+            // pattern tables
+            let addr = addr & 0x0FFF;
+            let slots = 0x1000 / size;
+            let register = (addr / size + 1) * (4 / slots) - 1;
+            self.background_chr_banks[register] as usize * size + addr % size
+        };
+        offset % self.rom.chr.len()
+    }
+
+    /// Called for every PPU read: detects scanlines and works out what is
+    /// being fetched.
+    fn track_ppu_read(&mut self, addr: u16) -> Fetch {
+        self.idle_cycles = 0;
+        if addr >= 0x2000 && addr == self.last_ppu_addr {
+            self.ppu_addr_matches += 1;
+            if self.ppu_addr_matches == 2 {
+                self.start_scanline();
+            }
+        } else {
+            self.ppu_addr_matches = 0;
+        }
+        self.last_ppu_addr = addr;
+
+        let read = self.ppu_reads;
+        self.ppu_reads += 1;
+        if !self.in_frame {
+            return Fetch::Other;
+        }
+        if read < SPRITE_FETCHES_START {
+            Fetch::Background {
+                column: read / 4 + 2,
+                next_line: false,
+                step: read % 4,
+            }
+        } else if read < SPRITE_FETCHES_END {
+            Fetch::Sprite
+        } else if read < NEXT_LINE_FETCHES_END {
+            Fetch::Background {
+                column: (read - SPRITE_FETCHES_END) / 4,
+                next_line: true,
+                step: read % 4,
+            }
+        } else {
+            Fetch::Other
+        }
+    }
+
+    fn start_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare {
+                self.irq_pending = true;
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        }
+        self.ppu_reads = 0;
+    }
+
+    /// Whether a background column is in the vertical split region
+    fn in_split(&self, column: usize) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return false;
+        }
+        let tiles = (self.split_control & 0x1F) as usize;
+        if self.split_control & 0x40 == 0 {
+            column < tiles
+        } else {
+            column >= tiles
+        }
+    }
+
+    /// The split region's Y coordinate for the current or the next line
+    fn split_y(&self, next_line: bool) -> usize {
+        let line = self.scanline as usize + next_line as usize;
+        (self.split_scroll as usize + line) % 240
+    }
+
+    /// Reads from the split region, which uses ExRAM as its nametable.
+    fn read_split(&self, addr: u16, column: usize, next_line: bool, step: usize) -> u8 {
+        let y = self.split_y(next_line);
+        let column = column % 32;
+        match step {
+            0 => self.exram[y / 8 * 32 + column],
+            1 => {
+                let attribute = self.exram[0x3C0 + y / 32 * 8 + column / 4];
+                let shift = (y / 16 % 2) * 4 + (column / 2 % 2) * 2;
+                // Return the palette for all four quadrants, since the PPU
+                // picks one using its own scroll position
+                ((attribute >> shift) & 0x03) * 0x55
+            }
+            _ => {
+                let offset =
+                    self.split_bank as usize * CHR_4K_BANK_SIZE + (addr as usize & 0x0FF8) + y % 8;
+                self.rom.chr[offset % self.rom.chr.len()]
+            }
+        }
+    }
+
+    /// Reads a nametable through the $5105 mapping.
+    fn read_nametable(&self, addr: u16) -> u8 {
+        let table = (addr as usize >> 10) & 3;
+        let offset = addr as usize & 0x03FF;
+        match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            page @ 0...1 => self.ciram[page as usize * 0x400 + offset],
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ if offset >= 0x3C0 => self.fill_attribute * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x5000...0x5015 => self.audio.write_register(addr, val),
+            0x5100 => self.prg_mode = val & 0x03,
+            0x5101 => self.chr_mode = val & 0x03,
+            0x5102 => self.prg_ram_protect[0] = val & 0x03,
+            0x5103 => self.prg_ram_protect[1] = val & 0x03,
+            0x5104 => self.exram_mode = val & 0x03,
+            0x5105 => self.nametable_mapping = val,
+            0x5106 => self.fill_tile = val,
+            0x5107 => self.fill_attribute = val & 0x03,
+            0x5113...0x5117 => self.prg_banks[addr as usize - 0x5113] = val,
+            0x5120...0x5127 => {
+                let bank = u16::from(val) | u16::from(self.chr_upper_bits) << 8;
+                self.sprite_chr_banks[addr as usize - 0x5120] = bank;
+                self.background_chr_last = false;
+            }
+            0x5128...0x512B => {
+                let bank = u16::from(val) | u16::from(self.chr_upper_bits) << 8;
+                self.background_chr_banks[addr as usize - 0x5128] = bank;
+                self.background_chr_last = true;
+            }
+            0x5130 => self.chr_upper_bits = val & 0x03,
+            0x5200 => self.split_control = val,
+            0x5201 => self.split_scroll = val,
+            0x5202 => self.split_bank = val,
+            0x5203 => self.irq_compare = val,
+            0x5204 => self.irq_enabled = val & 0x80 != 0,
+            0x5205 => self.multiplicand = val,
+            0x5206 => self.multiplier = val,
+            0x5C00...0x5FFF => {
+                let offset = addr as usize - 0x5C00;
+                match self.exram_mode {
+                    // Writable only while rendering, otherwise 0 is written
+                    0...1 => self.exram[offset] = if self.in_frame { val } else { 0 },
+                    2 => self.exram[offset] = val,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let val = self.peek(addr);
+        if let 0x8000...0xBFFF = addr {
+            self.audio.pcm_read(val);
+        }
+        val
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x2000...0x3FFF if addr & 0x07 == 0 => self.sprites_8x16 = val & 0x20 != 0,
+            0x5000...0x5FFF => self.write_register(addr, val),
+            0x6000...0xDFFF if self.prg_ram_writable() => {
+                if let (false, offset) = self.prg_offset(addr) {
+                    self.rom.sram[offset] = val;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0xFFFF => match self.prg_offset(addr) {
+                (true, offset) => self.rom.prg[offset],
+                (false, offset) => self.rom.sram[offset],
+            },
+            _ => 0,
+        }
+    }
+
+    fn expansion_read(&mut self, addr: u16) -> Option<u8> {
+        let val = self.expansion_peek(addr);
+        match addr {
+            0x5010 => {
+                self.audio.read_register(addr);
+            }
+            // Reading acknowledges the scanline IRQ
+            0x5204 => self.irq_pending = false,
+            _ => {}
+        }
+        val
+    }
+
+    fn expansion_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 | 0x5015 => self.audio.peek_register(addr),
+            0x5204 => Some((self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6),
+            0x5205 => Some((u16::from(self.multiplicand) * u16::from(self.multiplier)) as u8),
+            0x5206 => {
+                Some(((u16::from(self.multiplicand) * u16::from(self.multiplier)) >> 8) as u8)
+            }
+            0x5C00...0x5FFF if self.exram_mode >= 2 => Some(self.exram[addr as usize - 0x5C00]),
+            _ => None,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let fetch = self.track_ppu_read(addr);
+        if let Fetch::Background {
+            column,
+            next_line,
+            step,
+        } = fetch
+        {
+            if step == 0 {
+                self.tile_in_split = self.in_split(column);
+                self.tile_exram = self.exram[addr as usize & 0x03FF];
+            }
+            if self.tile_in_split {
+                return self.read_split(addr, column, next_line, step);
+            }
+            if self.exram_mode == 1 && step > 0 {
+                // Extended attributes: each tile's ExRAM byte selects its
+                // palette and 4 KB CHR bank (PPBBBBBB)
+                return if step == 1 {
+                    (self.tile_exram >> 6) * 0x55
+                } else {
+                    let bank =
+                        (self.tile_exram & 0x3F) as usize | (self.chr_upper_bits as usize) << 6;
+                    let offset = bank * CHR_4K_BANK_SIZE + (addr as usize & 0x0FFF);
+                    self.rom.chr[offset % self.rom.chr.len()]
+                };
+            }
+        }
+        if addr < 0x2000 {
+            self.rom.chr[self.chr_offset(addr, fetch)]
+        } else {
+            self.read_nametable(addr)
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        if addr < 0x2000 {
+            self.rom.chr[self.chr_offset(addr, Fetch::Other)]
+        } else {
+            self.read_nametable(addr)
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if addr < 0x2000 {
+            if self.rom.header.chr_ram() {
+                let offset = self.chr_offset(addr, Fetch::Other);
+                self.rom.chr[offset] = val;
+            }
+            return;
+        }
+        let table = (addr as usize >> 10) & 3;
+        let offset = addr as usize & 0x03FF;
+        match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            page @ 0...1 => self.ciram[page as usize * 0x400 + offset] = val,
+            2 if self.exram_mode <= 1 => self.exram[offset] = val,
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::MapperControlled
+    }
+
+    fn irq(&self) -> bool {
+        (self.irq_pending && self.irq_enabled) || self.audio.irq()
+    }
+
+    fn cpu_cycle(&mut self) {
+        if self.idle_cycles < IDLE_CYCLES {
+            self.idle_cycles += 1;
+            if self.idle_cycles == IDLE_CYCLES {
+                self.in_frame = false;
+                self.last_ppu_addr = 0;
+                self.ppu_addr_matches = 0;
+            }
+        }
+        self.audio.step();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_u8(self.prg_mode);
+        state.write_u8(self.chr_mode);
+        state.write_bytes(&self.prg_ram_protect);
+        state.write_u8(self.exram_mode);
+        state.write_u8(self.nametable_mapping);
+        state.write_u8(self.fill_tile);
+        state.write_u8(self.fill_attribute);
+        state.write_bytes(&self.prg_banks);
+        for &bank in self.sprite_chr_banks.iter() {
+            state.write_u16(bank);
+        }
+        for &bank in self.background_chr_banks.iter() {
+            state.write_u16(bank);
+        }
+        state.write_u8(self.chr_upper_bits);
+        state.write_bool(self.background_chr_last);
+        state.write_bool(self.sprites_8x16);
+        state.write_u8(self.split_control);
+        state.write_u8(self.split_scroll);
+        state.write_u8(self.split_bank);
+        state.write_u8(self.irq_compare);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_pending);
+        state.write_bool(self.in_frame);
+        state.write_u8(self.scanline);
+        state.write_u8(self.multiplicand);
+        state.write_u8(self.multiplier);
+        state.write_bytes(&self.exram);
+        state.write_bytes(&self.ciram);
+        self.audio.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.prg_mode = state.read_u8()?;
+        self.chr_mode = state.read_u8()?;
+        state.read_bytes(&mut self.prg_ram_protect)?;
+        self.exram_mode = state.read_u8()?;
+        self.nametable_mapping = state.read_u8()?;
+        self.fill_tile = state.read_u8()?;
+        self.fill_attribute = state.read_u8()?;
+        state.read_bytes(&mut self.prg_banks)?;
+        for bank in self.sprite_chr_banks.iter_mut() {
+            *bank = state.read_u16()?;
+        }
+        for bank in self.background_chr_banks.iter_mut() {
+            *bank = state.read_u16()?;
+        }
+        self.chr_upper_bits = state.read_u8()?;
+        self.background_chr_last = state.read_bool()?;
+        self.sprites_8x16 = state.read_bool()?;
+        self.split_control = state.read_u8()?;
+        self.split_scroll = state.read_u8()?;
+        self.split_bank = state.read_u8()?;
+        self.irq_compare = state.read_u8()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        self.in_frame = state.read_bool()?;
+        self.scanline = state.read_u8()?;
+        self.multiplicand = state.read_u8()?;
+        self.multiplier = state.read_u8()?;
+        state.read_bytes(&mut self.exram)?;
+        state.read_bytes(&mut self.ciram)?;
+        self.audio.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Mmc5;
+    use crate::mapper::Mapper;
+    use crate::rom::Rom;
+
+    /// A ROM with 8 KB PRG banks and 1 KB CHR banks filled with their number
+    fn mapper() -> Mmc5 {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 8, 8, 0x50, 0x00];
+        image.resize(16, 0);
+        for bank in 0..16 {
+            image.extend(vec![bank; 0x2000]);
+        }
+        for bank in 0..64 {
+            image.extend(vec![bank; 0x400]);
+        }
+        Mmc5::new(Rom::load(&mut &image[..]).unwrap())
+    }
+
+    /// Fetches a background tile, returning the attribute and the pattern
+    /// low byte.
+    fn fetch_tile(mapper: &mut Mmc5) -> (u8, u8) {
+        mapper.ppu_read(0x2000);
+        let attribute = mapper.ppu_read(0x23C0);
+        let pattern = mapper.ppu_read(0x0000);
+        mapper.ppu_read(0x0008);
+        (attribute, pattern)
+    }
+
+    /// Fetches a sprite, returning its pattern low byte.
+    fn fetch_sprite(mapper: &mut Mmc5) -> u8 {
+        mapper.ppu_read(0x2000);
+        mapper.ppu_read(0x2000);
+        let pattern = mapper.ppu_read(0x1000);
+        mapper.ppu_read(0x1008);
+        pattern
+    }
+
+    /// Makes the PPU reads of a rendering scanline, starting at the first
+    /// background fetch (which is the third read of the same nametable
+    /// address after the two unused fetches of the previous line). Returns
+    /// the attribute and pattern of the first tile and the pattern of the
+    /// first sprite.
+    fn render_line(mapper: &mut Mmc5) -> (u8, u8, u8) {
+        let (attribute, pattern) = fetch_tile(mapper);
+        for _ in 1..32 {
+            fetch_tile(mapper);
+        }
+        let sprite = fetch_sprite(mapper);
+        for _ in 1..8 {
+            fetch_sprite(mapper);
+        }
+        for _ in 0..2 {
+            fetch_tile(mapper);
+        }
+        mapper.ppu_read(0x2000);
+        mapper.ppu_read(0x2000);
+        (attribute, pattern, sprite)
+    }
+
+    /// Makes the unused nametable fetches at the end of the pre-render line.
+    fn start_frame(mapper: &mut Mmc5) {
+        mapper.ppu_read(0x2000);
+        mapper.ppu_read(0x2000);
+    }
+
+    fn prg_banks(mapper: &mut Mmc5) -> [u8; 4] {
+        [
+            mapper.cpu_read(0x8000),
+            mapper.cpu_read(0xA000),
+            mapper.cpu_read(0xC000),
+            mapper.cpu_read(0xE000),
+        ]
+    }
+
+    #[test]
+    fn prg_modes() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x5114, 0x81);
+        mapper.cpu_write(0x5115, 0x83);
+        mapper.cpu_write(0x5116, 0x89);
+        mapper.cpu_write(0x5117, 0x8F);
+        assert_eq!(prg_banks(&mut mapper), [1, 3, 9, 15]);
+        mapper.cpu_write(0x5100, 0);
+        assert_eq!(prg_banks(&mut mapper), [12, 13, 14, 15]);
+        mapper.cpu_write(0x5100, 1);
+        assert_eq!(prg_banks(&mut mapper), [2, 3, 14, 15]);
+        mapper.cpu_write(0x5100, 2);
+        assert_eq!(prg_banks(&mut mapper), [2, 3, 9, 15]);
+    }
+
+    #[test]
+    fn multiplier() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x5205, 200);
+        mapper.cpu_write(0x5206, 100);
+        // 20000 = $4E20
+        assert_eq!(mapper.expansion_read(0x5205), Some(0x20));
+        assert_eq!(mapper.expansion_read(0x5206), Some(0x4E));
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x5203, 3);
+        mapper.cpu_write(0x5204, 0x80);
+        assert_eq!(mapper.expansion_read(0x5204), Some(0x00));
+
+        start_frame(&mut mapper);
+        for _ in 0..3 {
+            render_line(&mut mapper);
+        }
+        assert_eq!(mapper.expansion_read(0x5204), Some(0x40));
+        assert!(!mapper.irq());
+        render_line(&mut mapper);
+        assert!(mapper.irq());
+        // Reading $5204 acknowledges the IRQ
+        assert_eq!(mapper.expansion_read(0x5204), Some(0xC0));
+        assert!(!mapper.irq());
+        assert_eq!(mapper.expansion_read(0x5204), Some(0x40));
+
+        // The frame ends when the PPU stops reading
+        for _ in 0..3 {
+            mapper.cpu_cycle();
+        }
+        assert_eq!(mapper.expansion_read(0x5204), Some(0x00));
+    }
+
+    #[test]
+    fn separate_chr_banks_for_8x16_sprites() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x5101, 3);
+        for i in 0..8 {
+            mapper.cpu_write(0x5120 + i, 0x10 + i as u8);
+        }
+        for i in 0..4 {
+            mapper.cpu_write(0x5128 + i, 0x20 + i as u8);
+        }
+
+        // With 8x8 sprites, the last set of registers written to is used,
+        // and the background banks map both pattern tables
+        start_frame(&mut mapper);
+        let (_, background, sprite) = render_line(&mut mapper);
+        assert_eq!((background, sprite), (0x20, 0x20));
+
+        mapper.cpu_write(0x2000, 0x20);
+        let (_, background, sprite) = render_line(&mut mapper);
+        assert_eq!((background, sprite), (0x20, 0x14));
+    }
+
+    #[test]
+    fn extended_attributes() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x5104, 0x01);
+        // ExRAM is only writable during rendering in this mode
+        mapper.cpu_write(0x5C00, 0xC5);
+        assert_eq!(mapper.exram[0], 0x00);
+        start_frame(&mut mapper);
+        render_line(&mut mapper);
+        mapper.cpu_write(0x5C00, 0xC5);
+
+        // Palette 3 and 4 KB CHR bank 5 for the tile at $2000
+        let (attribute, pattern, _) = render_line(&mut mapper);
+        assert_eq!(attribute, 0xFF);
+        assert_eq!(pattern, 5 * 4);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::apu::pulse::Pulse;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// CPU cycles between clocks of the envelopes and length counters (about
+/// 240 Hz). The MMC5 has no frame counter of its own to synchronize with.
+const FRAME_PERIOD: u16 = 7457;
+
+/// The MMC5's expansion audio: two pulse channels like the APU's, without
+/// sweep units, and an 8-bit PCM channel.
+///
+/// * $5000-$5003: Pulse 1
+/// * $5004-$5007: Pulse 2
+/// * $5010: PCM control (I------M: IRQ enable, read mode)
+/// * $5011: PCM raw data
+/// * $5015: Status (------21: pulse length counters)
+pub struct Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm: u8,
+    /// In read mode, the PCM channel plays the bytes the CPU reads from
+    /// $8000-$BFFF instead of the ones written to $5011.
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    /// Set when a 0 is read in read mode
+    pcm_irq: bool,
+    frame_divider: u16,
+    /// Whether the current CPU cycle is the second half of an APU cycle
+    odd_cycle: bool,
+}
+
+impl Audio {
+    pub fn new() -> Audio {
+        Audio {
+            pulse1: Pulse::without_sweep(),
+            pulse2: Pulse::without_sweep(),
+            pcm: 0,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: false,
+            frame_divider: 0,
+            odd_cycle: false,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x5000...0x5003 => self.pulse1.write_register(addr - 0x5000, val),
+            0x5004...0x5007 => self.pulse2.write_register(addr - 0x5004, val),
+            0x5010 => {
+                self.pcm_read_mode = val & 0x01 != 0;
+                self.pcm_irq_enabled = val & 0x80 != 0;
+            }
+            // Writing 0 has no effect
+            0x5011 if !self.pcm_read_mode && val != 0 => self.pcm = val,
+            0x5015 => {
+                self.pulse1.length_counter.set_enabled(val & 0x01 != 0);
+                self.pulse2.length_counter.set_enabled(val & 0x02 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles a read of $5010 or $5015, acknowledging the PCM IRQ.
+    pub fn read_register(&mut self, addr: u16) -> Option<u8> {
+        let val = self.peek_register(addr);
+        if addr == 0x5010 {
+            self.pcm_irq = false;
+        }
+        val
+    }
+
+    pub fn peek_register(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 => Some((self.irq() as u8) << 7),
+            0x5015 => Some(
+                self.pulse1.length_counter.active() as u8
+                    | (self.pulse2.length_counter.active() as u8) << 1,
+            ),
+            _ => None,
+        }
+    }
+
+    /// Called with every byte the CPU reads from $8000-$BFFF.
+    pub fn pcm_read(&mut self, val: u8) {
+        if self.pcm_read_mode {
+            if val == 0 {
+                self.pcm_irq = true;
+            } else {
+                self.pcm = val;
+            }
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.pcm_irq && self.pcm_irq_enabled
+    }
+
+    /// Advances the channels by one CPU cycle.
+    pub fn step(&mut self) {
+        self.frame_divider += 1;
+        if self.frame_divider == FRAME_PERIOD {
+            self.frame_divider = 0;
+            self.pulse1.clock_quarter_frame();
+            self.pulse1.clock_half_frame();
+            self.pulse2.clock_quarter_frame();
+            self.pulse2.clock_half_frame();
+        }
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+    }
+
+    /// The mixed output level. The pulses go through the same kind of
+    /// resistor network as the APU's, and the PCM channel is about as loud
+    /// as the DMC.
+    pub fn output(&self) -> f32 {
+        let pulse = f32::from(self.pulse1.output() + self.pulse2.output());
+        let pulse = if pulse > 0.0 {
+            95.52 / (8128.0 / pulse + 100.0)
+        } else {
+            0.0
+        };
+        let pcm = f32::from(self.pcm / 2);
+        let pcm = if pcm > 0.0 {
+            163.67 / (24329.0 / pcm + 100.0)
+        } else {
+            0.0
+        };
+        pulse + pcm
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        state.write_u8(self.pcm);
+        state.write_bool(self.pcm_read_mode);
+        state.write_bool(self.pcm_irq_enabled);
+        state.write_bool(self.pcm_irq);
+        state.write_u16(self.frame_divider);
+        state.write_bool(self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.pcm = state.read_u8()?;
+        self.pcm_read_mode = state.read_bool()?;
+        self.pcm_irq_enabled = state.read_bool()?;
+        self.pcm_irq = state.read_bool()?;
+        self.frame_divider = state.read_u16()?;
+        self.odd_cycle = state.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+mod axrom;
+mod bnrom;
+mod camerica;
+mod cnrom;
+mod color_dreams;
+mod fme7;
+mod gxrom;
+mod mmc1;
+mod mmc2;
+mod mmc3;
+mod mmc5;
+mod namco163;
+mod uxrom;
+mod vrc4;
+mod vrc6;
+mod vrc7;
+mod vrc_irq;
+
+pub use self::axrom::Axrom;
+pub use self::bnrom::Bnrom;
+pub use self::camerica::Camerica;
+pub use self::cnrom::Cnrom;
+pub use self::color_dreams::ColorDreams;
+pub use self::fme7::Fme7;
+pub use self::gxrom::Gxrom;
+pub use self::mmc1::Mmc1;
+pub use self::mmc2::Mmc2;
+pub use self::mmc3::Mmc3;
+pub use self::mmc5::Mmc5;
+pub use self::namco163::Namco163;
+pub use self::uxrom::Uxrom;
+pub use self::vrc4::Vrc4;
+pub use self::vrc6::Vrc6;
+pub use self::vrc7::Vrc7;
+
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub fn init(rom: Rom) -> Box<Mapper> {
+    match rom.header.mapper() {
+        0 => Box::new(MapperZero::new(rom)),
+        1 => Box::new(Mmc1::new(rom)),
+        2 => Box::new(Uxrom::new(rom)),
+        3 => Box::new(Cnrom::new(rom)),
+        4 => Box::new(Mmc3::new(rom)),
+        5 => Box::new(Mmc5::new(rom)),
+        7 => Box::new(Axrom::new(rom)),
+        9 | 10 => Box::new(Mmc2::new(rom)),
+        11 => Box::new(ColorDreams::new(rom)),
+        19 => Box::new(Namco163::new(rom)),
+        21 | 22 | 23 | 25 => Box::new(Vrc4::new(rom)),
+        24 | 26 => Box::new(Vrc6::new(rom)),
+        34 => Box::new(Bnrom::new(rom)),
+        66 => Box::new(Gxrom::new(rom)),
+        69 => Box::new(Fme7::new(rom)),
+        71 => Box::new(Camerica::new(rom)),
+        85 => Box::new(Vrc7::new(rom)),
+        id @ _ => panic!("Unimplemented mapper {}", id),
+    }
+}
+
+/// Returns the value a discrete-logic board latches when `val` is written to
+/// its ROM space.
+///
+/// Unless the board disables the ROM during writes, the ROM drives the data
+/// bus with `rom_val` at the same time as the CPU, and the result is the AND
+/// of both. This is only emulated if enabled for the cartridge.
+fn bus_conflict(rom: &Rom, rom_val: u8, val: u8) -> u8 {
+    if rom.bus_conflicts {
+        rom_val & val
+    } else {
+        val
+    }
+}
+
+/// Maps the address of a Konami VRC register to $x000-$x003.
+///
+/// The VRC chips have two register select pins, which the boards connect to
+/// different CPU address lines: `a0` and `a1` are masks of the lines wired to
+/// each pin (several lines when the board variant is unknown).
+fn vrc_register(addr: u16, a0: u16, a1: u16) -> u16 {
+    (addr & 0xF000) | (addr & a0 != 0) as u16 | ((addr & a1 != 0) as u16) << 1
+}
+
+/// How the PPU's four logical nametables ($2000-$2FFF) are mapped onto the
+/// console's 2 KB of nametable VRAM.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mirroring {
+    /// $2000 and $2400 share a page, as do $2800 and $2C00 (vertical
+    /// arrangement)
+    Horizontal,
+    /// $2000 and $2800 share a page, as do $2400 and $2C00 (horizontal
+    /// arrangement)
+    Vertical,
+    /// All four nametables use the first page
+    SingleScreenA,
+    /// All four nametables use the second page
+    SingleScreenB,
+    /// Four distinct nametables, using 2 KB of extra VRAM on the cartridge
+    FourScreen,
+    /// The mapper handles nametable accesses itself, through
+    /// `Mapper::ppu_read` and `Mapper::ppu_write`
+    MapperControlled,
+}
+
+impl Mirroring {
+    /// Encodes the mirroring for a saved state.
+    pub fn save_state(self, state: &mut StateWriter) {
+        state.write_u8(self as u8);
+    }
+
+    pub fn load_state(state: &mut StateReader) -> Result<Mirroring, StateError> {
+        Ok(match state.read_u8()? {
+            0 => Mirroring::Horizontal,
+            1 => Mirroring::Vertical,
+            2 => Mirroring::SingleScreenA,
+            3 => Mirroring::SingleScreenB,
+            4 => Mirroring::FourScreen,
+            5 => Mirroring::MapperControlled,
+            _ => return Err(StateError::Mismatch),
+        })
+    }
+
+    /// Maps a nametable address ($2000-$3EFF) to an offset into 4 KB of
+    /// nametable VRAM.
+    pub fn nametable_offset(self, addr: u16) -> usize {
+        let addr = (addr as usize - 0x2000) % 0x1000;
+        let table = addr / 0x400;
+        let page = match self {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::SingleScreenA => 0,
+            Mirroring::SingleScreenB => 1,
+            Mirroring::FourScreen | Mirroring::MapperControlled => table,
+        };
+        page * 0x400 + addr % 0x400
+    }
+}
+
+/// A cartridge board, connected to both the CPU and the PPU buses.
+///
+/// The CPU side covers $4020-$FFFF (expansion space, PRG-RAM and PRG-ROM),
+/// the PPU side $0000-$1FFF (pattern tables) and, with
+/// `Mirroring::MapperControlled`, the nametables at $2000-$3EFF.
+pub trait Mapper {
+    /// Handles a CPU read. Reads have side effects on some boards (e.g.
+    /// acknowledging an IRQ).
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+    /// Handles a CPU write to $4020-$FFFF. Writes to the PPU registers
+    /// ($2000-$3FFF) are passed on as well, for boards that snoop them.
+    fn cpu_write(&mut self, addr: u16, val: u8);
+    /// Returns the byte `cpu_read` would return, without side effects, for
+    /// tracing and debuggers.
+    fn peek(&self, addr: u16) -> u8;
+    /// Handles a CPU read of the expansion space ($4020-$5FFF), which most
+    /// boards leave unconnected: `None` reads as open bus.
+    fn expansion_read(&mut self, addr: u16) -> Option<u8> {
+        self.expansion_peek(addr)
+    }
+    /// Returns the byte `expansion_read` would return, without side effects.
+    fn expansion_peek(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+    /// Handles a PPU read. Some boards react to the addresses the PPU
+    /// fetches (e.g. the MMC2's CHR latches).
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+    /// Returns the byte `ppu_read` would return, without side effects.
+    fn ppu_peek(&self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, val: u8);
+    /// The current nametable mirroring, which some mappers can change at
+    /// runtime.
+    fn mirroring(&self) -> Mirroring;
+    /// Level of the mapper's IRQ output
+    fn irq(&self) -> bool {
+        false
+    }
+    /// Called once per CPU cycle, after the PPU has caught up with it.
+    fn cpu_cycle(&mut self) {}
+    /// Called with the level of PPU A12 on every PPU memory access and when
+    /// the VRAM address is set through $2006 (e.g. the MMC3 counts rising
+    /// edges of A12 to count scanlines).
+    fn ppu_a12(&mut self, _high: bool) {}
+    /// Output level of the board's expansion audio, added to the APU's
+    /// output (on the same 0.0-1.0 scale).
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+    /// The battery-backed PRG-RAM, which is saved to disk.
+    fn sram(&mut self) -> &mut [u8];
+    /// RAM inside the mapper chip which is battery-backed along with the
+    /// PRG-RAM on some boards (e.g. the Namco 163's sound RAM, which games
+    /// also use for saves).
+    fn internal_ram(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+    /// Serializes the board's registers and RAM.
+    fn save_state(&self, state: &mut StateWriter);
+    /// Restores a state written by `save_state`.
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// NROM (mapper 0): 16 or 32 KB of PRG-ROM, 8 KB of CHR and no registers.
+pub struct MapperZero {
+    rom: Rom,
+}
+
+impl MapperZero {
+    pub fn new(rom: Rom) -> MapperZero {
+        MapperZero { rom: rom }
+    }
+}
+
+impl Mapper for MapperZero {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF => self.rom.sram[(addr as usize - 0x6000) % self.rom.sram.len()],
+            // 16 KB of PRG-ROM is mirrored at $C000
+            0x8000...0xFFFF => self.rom.prg[(addr as usize - 0x8000) % self.rom.prg.len()],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if let 0x6000...0x7FFF = addr {
+            let len = self.rom.sram.len();
+            self.rom.sram[(addr as usize - 0x6000) % len] = val;
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[addr as usize]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            self.rom.chr[addr as usize] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.header.mirroring()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::bus_conflict;
+    use crate::rom::Rom;
+
+    #[test]
+    fn bus_conflicts() {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x00, 0x00];
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        let mut rom = Rom::load(&mut &image[..]).unwrap();
+        assert_eq!(bus_conflict(&rom, 0x0F, 0x35), 0x35);
+        rom.bus_conflicts = true;
+        assert_eq!(bus_conflict(&rom, 0x0F, 0x35), 0x05);
+    }
+}
@@ -0,0 +1,334 @@
+mod audio;
+
+use self::audio::Audio;
+use super::{Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+/// Size of the protection units of the PRG-RAM
+const PRG_RAM_PROTECT_SIZE: usize = 0x0800;
+const CIRAM_SIZE: usize = 0x0800;
+/// Banks numbers from $E0 select the console's nametable RAM instead of
+/// CHR-ROM
+const CIRAM_BANKS: u8 = 0xE0;
+
+/// Namco 163 (mapper 19), with wavetable expansion audio.
+///
+/// * $4800-$4FFF: Internal RAM data port (see `audio::Audio`)
+/// * $5000-$57FF: IRQ counter low 8 bits
+/// * $5800-$5FFF: IRQ counter high 7 bits and enable (bit 7)
+/// * $8000-$BFFF: CHR banks 0-7, 1 KB each, every $800 bytes
+/// * $C000-$DFFF: Nametable banks 0-3, every $800 bytes
+/// * $E000-$E7FF: PRG bank at $8000 (-SBBBBBB: sound disable, bank)
+/// * $E800-$EFFF: PRG bank at $A000 (HLBBBBBB: use CHR-ROM for banks $E0-$FF
+///   at $1000 and $0000, bank)
+/// * $F000-$F7FF: PRG bank at $C000
+/// * $F800-$FFFF: Internal RAM address, and PRG-RAM write protection (writes
+///   are enabled by $4x, with bits 0-3 protecting 2 KB each)
+///
+/// The last 8 KB PRG bank is fixed at $E000. Pattern table and nametable
+/// banks can point to either CHR-ROM or one of the two pages of nametable
+/// RAM. The 15-bit IRQ counter counts up every CPU cycle and raises the IRQ
+/// when it reaches $7FFF.
+pub struct Namco163 {
+    rom: Rom,
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    prg_banks: [u8; 3],
+    sound_disabled: bool,
+    /// $E800 bits 6 and 7
+    chr_ram_disabled: [bool; 2],
+    write_protect: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq: bool,
+    /// The console's nametable RAM, which the chip maps itself
+    ciram: Vec<u8>,
+    audio: Audio,
+}
+
+impl Namco163 {
+    pub fn new(rom: Rom) -> Namco163 {
+        Namco163 {
+            rom: rom,
+            chr_banks: [0; 8],
+            nametable_banks: [0; 4],
+            prg_banks: [0; 3],
+            sound_disabled: false,
+            chr_ram_disabled: [false; 2],
+            write_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq: false,
+            ciram: vec![0; CIRAM_SIZE],
+            audio: Audio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = self.rom.prg.len() / PRG_BANK_SIZE;
+        let bank = match addr {
+            0x8000...0xDFFF => self.prg_banks[(addr as usize - 0x8000) / PRG_BANK_SIZE] as usize,
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let unit = (addr as usize - 0x6000) / PRG_RAM_PROTECT_SIZE;
+        self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << unit) == 0
+    }
+
+    /// Returns the offset of a PPU address ($0000-$2FFF) into either
+    /// nametable RAM (true) or CHR.
+    fn ppu_offset(&self, addr: u16) -> (bool, usize) {
+        let (bank, ciram_allowed) = if addr < 0x2000 {
+            let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE];
+            (bank, !self.chr_ram_disabled[addr as usize / 0x1000])
+        } else {
+            (self.nametable_banks[(addr as usize >> 10) & 3], true)
+        };
+        let offset = addr as usize & (CHR_BANK_SIZE - 1);
+        if bank >= CIRAM_BANKS && ciram_allowed {
+            (true, (bank as usize & 1) * CHR_BANK_SIZE + offset)
+        } else {
+            (
+                false,
+                (bank as usize * CHR_BANK_SIZE + offset) % self.rom.chr.len(),
+            )
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x8000...0xBFFF => self.chr_banks[(addr as usize - 0x8000) / 0x800] = val,
+            0xC000...0xDFFF => self.nametable_banks[(addr as usize - 0xC000) / 0x800] = val,
+            0xE000...0xE7FF => {
+                self.prg_banks[0] = val & 0x3F;
+                self.sound_disabled = val & 0x40 != 0;
+            }
+            0xE800...0xEFFF => {
+                self.prg_banks[1] = val & 0x3F;
+                self.chr_ram_disabled = [val & 0x40 != 0, val & 0x80 != 0];
+            }
+            0xF000...0xF7FF => self.prg_banks[2] = val & 0x3F,
+            _ => {
+                self.write_protect = val;
+                self.audio.write_address(val);
+            }
+        }
+    }
+}
+
+impl Mapper for Namco163 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF => self.rom.sram[(addr as usize - 0x6000) % self.rom.sram.len()],
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4800...0x4FFF => self.audio.write_data(val),
+            // Writing the IRQ counter acknowledges the IRQ
+            0x5000...0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | u16::from(val);
+                self.irq = false;
+            }
+            0x5800...0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | u16::from(val & 0x7F) << 8;
+                self.irq_enabled = val & 0x80 != 0;
+                self.irq = false;
+            }
+            0x6000...0x7FFF if self.prg_ram_writable(addr) => {
+                let len = self.rom.sram.len();
+                self.rom.sram[(addr as usize - 0x6000) % len] = val;
+            }
+            0x8000...0xFFFF => self.write_register(addr, val),
+            _ => {}
+        }
+    }
+
+    fn expansion_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800...0x4FFF => Some(self.audio.read_data()),
+            _ => self.expansion_peek(addr),
+        }
+    }
+
+    fn expansion_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800...0x4FFF => Some(self.audio.peek_data()),
+            0x5000...0x57FF => Some(self.irq_counter as u8),
+            0x5800...0x5FFF => Some((self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7),
+            _ => None,
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        match self.ppu_offset(addr & 0x2FFF) {
+            (true, offset) => self.ciram[offset],
+            (false, offset) => self.rom.chr[offset],
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        match self.ppu_offset(addr & 0x2FFF) {
+            (true, offset) => self.ciram[offset] = val,
+            (false, offset) => {
+                if self.rom.header.chr_ram() {
+                    self.rom.chr[offset] = val;
+                }
+            }
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::MapperControlled
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+
+    fn cpu_cycle(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                self.irq = true;
+            }
+        }
+        if !self.sound_disabled {
+            self.audio.step();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_disabled {
+            0.0
+        } else {
+            self.audio.output()
+        }
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn internal_ram(&mut self) -> Option<&mut [u8]> {
+        Some(self.audio.ram())
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_bytes(&self.chr_banks);
+        state.write_bytes(&self.nametable_banks);
+        state.write_bytes(&self.prg_banks);
+        state.write_bool(self.sound_disabled);
+        state.write_bool(self.chr_ram_disabled[0]);
+        state.write_bool(self.chr_ram_disabled[1]);
+        state.write_u8(self.write_protect);
+        state.write_u16(self.irq_counter);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq);
+        state.write_bytes(&self.ciram);
+        self.audio.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        state.read_bytes(&mut self.chr_banks)?;
+        state.read_bytes(&mut self.nametable_banks)?;
+        state.read_bytes(&mut self.prg_banks)?;
+        self.sound_disabled = state.read_bool()?;
+        self.chr_ram_disabled[0] = state.read_bool()?;
+        self.chr_ram_disabled[1] = state.read_bool()?;
+        self.write_protect = state.read_u8()?;
+        self.irq_counter = state.read_u16()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq = state.read_bool()?;
+        state.read_bytes(&mut self.ciram)?;
+        self.audio.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Namco163;
+    use crate::mapper::Mapper;
+    use crate::rom::Rom;
+
+    /// A ROM with 1 KB CHR banks filled with their number
+    fn mapper() -> Namco163 {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 2, 32, 0x30, 0x10];
+        image.resize(16 + 2 * 0x4000, 0);
+        for bank in 0..=255 {
+            image.extend(vec![bank; 0x400]);
+        }
+        Namco163::new(Rom::load(&mut &image[..]).unwrap())
+    }
+
+    #[test]
+    fn irq_counter_stops_at_7fff() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x5000, 0xFD);
+        mapper.cpu_write(0x5800, 0xFF);
+        mapper.cpu_cycle();
+        assert!(!mapper.irq());
+        mapper.cpu_cycle();
+        assert!(mapper.irq());
+        mapper.cpu_cycle();
+        assert_eq!(mapper.expansion_peek(0x5000), Some(0xFF));
+        assert_eq!(mapper.expansion_peek(0x5800), Some(0xFF));
+        // Writing the counter acknowledges the IRQ
+        mapper.cpu_write(0x5000, 0x00);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn internal_ram_data_port() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0xF800, 0x90);
+        for val in 1..=3 {
+            mapper.cpu_write(0x4800, val);
+        }
+        mapper.cpu_write(0xF800, 0x90);
+        let data: Vec<_> = (0..3).map(|_| mapper.expansion_read(0x4800)).collect();
+        assert_eq!(data, [Some(1), Some(2), Some(3)]);
+
+        // Without auto-increment, the address stays put
+        mapper.cpu_write(0xF800, 0x20);
+        mapper.cpu_write(0x4800, 4);
+        mapper.cpu_write(0x4800, 5);
+        assert_eq!(mapper.expansion_read(0x4800), Some(5));
+        assert_eq!(mapper.expansion_read(0x4800), Some(5));
+        assert_eq!(mapper.audio.ram()[0x21], 0);
+    }
+
+    #[test]
+    fn nametable_ram_or_chr() {
+        let mut mapper = mapper();
+        // Banks from $E0 select nametable RAM pages by their low bit
+        mapper.cpu_write(0x8000, 0xE1);
+        mapper.cpu_write(0x8800, 0x05);
+        mapper.cpu_write(0xC000, 0xE1);
+        mapper.cpu_write(0xC800, 0xE0);
+        mapper.ppu_write(0x2000, 0xAA);
+        assert_eq!(mapper.ppu_peek(0x0000), 0xAA);
+        assert_eq!(mapper.ppu_peek(0x0400), 0x05);
+        assert_eq!(mapper.ppu_peek(0x2400), 0x00);
+
+        // $E800 bit 6 makes the banks at $0000-$0FFF use CHR-ROM
+        mapper.cpu_write(0xE800, 0x40);
+        assert_eq!(mapper.ppu_peek(0x0000), 0xE1);
+        // but not the nametables
+        assert_eq!(mapper.ppu_peek(0x2000), 0xAA);
+        mapper.cpu_write(0xC000, 0x07);
+        assert_eq!(mapper.ppu_peek(0x2000), 0x07);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Size of the internal RAM, shared by the CPU data port and the sound
+/// channels
+pub const RAM_SIZE: usize = 0x80;
+/// CPU cycles between the updates of two channels
+const CYCLES_PER_CHANNEL: u8 = 15;
+/// Output level of a full-scale sample at full volume, relative to the
+/// APU's output
+const LEVEL_SCALE: f32 = 0.3 / 120.0;
+
+/// The Namco 163's wavetable synthesizer.
+///
+/// Up to 8 channels play 4-bit samples from the internal RAM, which also
+/// holds their registers, 8 bytes per channel from $40 (channel 1) to $78
+/// (channel 8):
+///
+/// * +0, +2, +4 (bits 0-1): 18-bit frequency
+/// * +1, +3, +5: 24-bit phase
+/// * +4 (bits 2-7): Wave length (256 - 4 * n samples)
+/// * +6: Wave address, in 4-bit samples (low nibble first)
+/// * +7: Volume (bits 0-3); bits 4-6 of $7F also hold the number of enabled
+///   channels minus 1
+///
+/// The chip updates one channel every 15 CPU cycles, going down from channel
+/// 8 through the enabled ones, and outputs them in turn. Their average is
+/// used here.
+pub struct Audio {
+    ram: [u8; RAM_SIZE],
+    /// RAM address of the data port
+    address: u8,
+    auto_increment: bool,
+    divider: u8,
+    /// Channel to update next (0-7)
+    channel: usize,
+    outputs: [i16; 8],
+}
+
+impl Audio {
+    pub fn new() -> Audio {
+        Audio {
+            ram: [0; RAM_SIZE],
+            address: 0,
+            auto_increment: false,
+            divider: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    pub fn ram(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    /// Handles a write to $F800 (AAAAAAAI: auto-increment, address).
+    pub fn write_address(&mut self, val: u8) {
+        self.address = val & 0x7F;
+        self.auto_increment = val & 0x80 != 0;
+    }
+
+    fn increment_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+
+    /// Reads the data port ($4800).
+    pub fn read_data(&mut self) -> u8 {
+        let val = self.peek_data();
+        self.increment_address();
+        val
+    }
+
+    pub fn peek_data(&self) -> u8 {
+        self.ram[self.address as usize]
+    }
+
+    /// Writes the data port ($4800).
+    pub fn write_data(&mut self, val: u8) {
+        self.ram[self.address as usize] = val;
+        self.increment_address();
+    }
+
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0x07) as usize + 1
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x40 + channel * 8;
+        let ram = &mut self.ram;
+        let frequency = u32::from(ram[base])
+            | u32::from(ram[base + 2]) << 8
+            | u32::from(ram[base + 4] & 0x03) << 16;
+        let phase = u32::from(ram[base + 1])
+            | u32::from(ram[base + 3]) << 8
+            | u32::from(ram[base + 5]) << 16;
+        let length = 256 - u32::from(ram[base + 4] & 0xFC);
+        let phase = (phase + frequency) % (length << 16);
+        ram[base + 1] = phase as u8;
+        ram[base + 3] = (phase >> 8) as u8;
+        ram[base + 5] = (phase >> 16) as u8;
+
+        let sample_addr = (u32::from(ram[base + 6]) + (phase >> 16)) as usize & 0xFF;
+        let sample = (ram[sample_addr / 2] >> ((sample_addr & 1) * 4)) & 0x0F;
+        let volume = ram[base + 7] & 0x0F;
+        self.outputs[channel] = (i16::from(sample) - 8) * i16::from(volume);
+    }
+
+    /// Advances the chip by one CPU cycle.
+    pub fn step(&mut self) {
+        self.divider += 1;
+        if self.divider < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.divider = 0;
+        let channel = self.channel;
+        self.update_channel(channel);
+        self.channel = if channel <= 8 - self.enabled_channels() {
+            7
+        } else {
+            channel - 1
+        };
+    }
+
+    pub fn output(&self) -> f32 {
+        let count = self.enabled_channels();
+        let sum: i16 = self.outputs[8 - count..].iter().sum();
+        f32::from(sum) / count as f32 * LEVEL_SCALE
+    }
+
+    /// Saves the RAM and the data port, which hold the state the CPU can
+    /// see. The channels resume from there.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_u8(self.address);
+        state.write_bool(self.auto_increment);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.ram)?;
+        self.address = state.read_u8()?;
+        self.auto_increment = state.read_bool()?;
+        Ok(())
+    }
+}
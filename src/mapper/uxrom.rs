@@ -0,0 +1,77 @@
+use super::{bus_conflict, Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+/// UxROM (mapper 2): a switchable 16 KB PRG bank at $8000 and the last bank
+/// fixed at $C000, selected by writing to $8000-$FFFF. CHR is 8 KB, usually
+/// RAM.
+pub struct Uxrom {
+    rom: Rom,
+    prg_bank: usize,
+}
+
+impl Uxrom {
+    pub fn new(rom: Rom) -> Uxrom {
+        Uxrom {
+            rom: rom,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = self.rom.prg.len() / PRG_BANK_SIZE;
+        let bank = if addr < 0xC000 {
+            self.prg_bank % bank_count
+        } else {
+            bank_count - 1
+        };
+        bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+}
+
+impl Mapper for Uxrom {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            let val = bus_conflict(&self.rom, self.peek(addr), val);
+            self.prg_bank = val as usize;
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[addr as usize]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            self.rom.chr[addr as usize] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.header.mirroring()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_usize(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.prg_bank = state.read_usize()?;
+        Ok(())
+    }
+}
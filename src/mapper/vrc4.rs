@@ -0,0 +1,271 @@
+use super::vrc_irq::VrcIrq;
+use super::{vrc_register, Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25).
+///
+/// * $8000-$8003: PRG bank at $8000 (or $C000 in swap mode)
+/// * $9000-$9001: Mirroring (VRC2: vertical or horizontal, VRC4: also
+///   single-screen)
+/// * $9002: PRG swap mode (VRC4, ------M-)
+/// * $A000-$A003: PRG bank at $A000
+/// * $B000-$E003: CHR banks 0-7, 1 KB each, written a nibble at a time (low
+///   nibble at even, high nibble at odd registers)
+/// * $F000-$F003: IRQ latch low and high nibbles, control and acknowledge
+///   (VRC4)
+///
+/// The second last 8 KB PRG bank is fixed at $C000 (or $8000), the last one
+/// at $E000.
+///
+/// The boards connect the register select pins to different address lines,
+/// which the NES 2.0 submapper tells:
+///
+/// * 21: VRC4a (A1, A2), VRC4c (A6, A7)
+/// * 22: VRC2a (A1, A0), which also ignores the low bit of the CHR banks
+/// * 23: VRC4f (A0, A1), VRC4e (A2, A3), VRC2b (A0, A1)
+/// * 25: VRC4b (A1, A0), VRC4d (A3, A2), VRC2c (A1, A0)
+///
+/// Without a submapper, all the lines of the mapper number's variants are
+/// used, which works as long as games only write to the canonical addresses.
+pub struct Vrc4 {
+    rom: Rom,
+    /// The VRC2 has no IRQ and only two mirroring modes
+    vrc2: bool,
+    a0: u16,
+    a1: u16,
+    /// Right shift applied to the CHR banks (VRC2a)
+    chr_shift: u8,
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(rom: Rom) -> Vrc4 {
+        let (vrc2, a0, a1) = match (rom.header.mapper(), rom.header.submapper()) {
+            (21, 1) => (false, 0x02, 0x04),
+            (21, 2) => (false, 0x40, 0x80),
+            (21, _) => (false, 0x42, 0x84),
+            (22, _) => (true, 0x02, 0x01),
+            (23, 1) => (false, 0x01, 0x02),
+            (23, 2) => (false, 0x04, 0x08),
+            (23, 3) => (true, 0x01, 0x02),
+            (23, _) => (false, 0x05, 0x0A),
+            (25, 1) => (false, 0x02, 0x01),
+            (25, 2) => (false, 0x08, 0x04),
+            (25, 3) => (true, 0x02, 0x01),
+            (_, _) => (false, 0x0A, 0x05),
+        };
+        let chr_shift = (rom.header.mapper() == 22) as u8;
+        let mirroring = rom.header.mirroring();
+        Vrc4 {
+            rom: rom,
+            vrc2: vrc2,
+            a0: a0,
+            a1: a1,
+            chr_shift: chr_shift,
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: mirroring,
+            irq: VrcIrq::default(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = self.rom.prg.len() / PRG_BANK_SIZE;
+        let second_last = bank_count - 2;
+        let bank = match (addr, self.prg_swap) {
+            (0x8000...0x9FFF, false) | (0xC000...0xDFFF, true) => self.prg_banks[0] as usize,
+            (0x8000...0x9FFF, true) | (0xC000...0xDFFF, false) => second_last,
+            (0xA000...0xBFFF, _) => self.prg_banks[1] as usize,
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = (self.chr_banks[addr as usize / CHR_BANK_SIZE] >> self.chr_shift) as usize;
+        (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.rom.chr.len()
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match vrc_register(addr, self.a0, self.a1) {
+            0x8000...0x8003 => self.prg_banks[0] = val & 0x1F,
+            0x9000...0x9003 if self.vrc2 => {
+                self.mirroring = if val & 0x01 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            0x9000...0x9001 => {
+                self.mirroring = match val & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                };
+            }
+            0x9002 => self.prg_swap = val & 0x02 != 0,
+            0xA000...0xA003 => self.prg_banks[1] = val & 0x1F,
+            reg @ 0xB000...0xEFFF => {
+                let bank = ((reg - 0xB000) >> 12) as usize * 2 + (reg as usize & 0x02) / 2;
+                let chr_bank = &mut self.chr_banks[bank];
+                *chr_bank = if reg & 0x01 == 0 {
+                    (*chr_bank & 0x1F0) | u16::from(val & 0x0F)
+                } else {
+                    (*chr_bank & 0x0F) | u16::from(val & 0x1F) << 4
+                };
+            }
+            _ if self.vrc2 => {}
+            0xF000 => self.irq.write_latch_low(val),
+            0xF001 => self.irq.write_latch_high(val),
+            0xF002 => self.irq.write_control(val),
+            0xF003 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF => self.rom.sram[(addr as usize - 0x6000) % self.rom.sram.len()],
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF => {
+                let len = self.rom.sram.len();
+                self.rom.sram[(addr as usize - 0x6000) % len] = val;
+            }
+            0x8000...0xFFFF => self.write_register(addr, val),
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.irq.cpu_cycle();
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_bytes(&self.prg_banks);
+        state.write_bool(self.prg_swap);
+        for &bank in self.chr_banks.iter() {
+            state.write_u16(bank);
+        }
+        self.mirroring.save_state(state);
+        self.irq.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        state.read_bytes(&mut self.prg_banks)?;
+        self.prg_swap = state.read_bool()?;
+        for bank in self.chr_banks.iter_mut() {
+            *bank = state.read_u16()?;
+        }
+        self.mirroring = Mirroring::load_state(state)?;
+        self.irq.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Vrc4;
+    use crate::mapper::Mapper;
+    use crate::rom::Rom;
+
+    /// A ROM with 1 KB CHR banks filled with their number, with an NES 2.0
+    /// header if `submapper` isn't 0.
+    fn mapper(mapper: u8, submapper: u8) -> Vrc4 {
+        let nes2 = if submapper == 0 { 0x00 } else { 0x08 };
+        let mut image = vec![
+            0x4E,
+            0x45,
+            0x53,
+            0x1A,
+            2,
+            4,
+            mapper << 4,
+            (mapper & 0xF0) | nes2,
+            submapper << 4,
+        ];
+        image.resize(16 + 2 * 0x4000, 0);
+        for bank in 0..32 {
+            image.extend(vec![bank; 0x400]);
+        }
+        Vrc4::new(Rom::load(&mut &image[..]).unwrap())
+    }
+
+    #[test]
+    fn register_address_lines() {
+        // Mapper, submapper, CPU address lines of A0 and A1, and the CHR
+        // banks selected by the writes below
+        let boards = [
+            (21, 1, 0x02, 0x04, 0x10, 3),
+            (21, 2, 0x40, 0x80, 0x10, 3),
+            (21, 0, 0x02, 0x04, 0x10, 3),
+            (21, 0, 0x40, 0x80, 0x10, 3),
+            (22, 0, 0x02, 0x01, 0x08, 1),
+            (23, 1, 0x01, 0x02, 0x10, 3),
+            (23, 2, 0x04, 0x08, 0x10, 3),
+            (23, 3, 0x01, 0x02, 0x10, 3),
+            (23, 0, 0x01, 0x02, 0x10, 3),
+            (23, 0, 0x04, 0x08, 0x10, 3),
+            (25, 1, 0x02, 0x01, 0x10, 3),
+            (25, 2, 0x08, 0x04, 0x10, 3),
+            (25, 3, 0x02, 0x01, 0x10, 3),
+            (25, 0, 0x02, 0x01, 0x10, 3),
+            (25, 0, 0x08, 0x04, 0x10, 3),
+        ];
+        for &(number, submapper, a0, a1, bank0, bank1) in boards.iter() {
+            let mut mapper = mapper(number, submapper);
+            // $B001: CHR bank 0 high nibble, $B002: CHR bank 1 low nibble
+            mapper.cpu_write(0xB000 | a0, 0x01);
+            mapper.cpu_write(0xB000 | a1, 0x03);
+            assert_eq!(
+                (mapper.ppu_peek(0x0000), mapper.ppu_peek(0x0400)),
+                (bank0, bank1),
+                "mapper {} submapper {}",
+                number,
+                submapper
+            );
+        }
+    }
+}
@@ -0,0 +1,181 @@
+mod audio;
+
+use self::audio::Audio;
+use super::vrc_irq::VrcIrq;
+use super::{vrc_register, Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// Konami VRC6 (mappers 24 and 26), with expansion audio.
+///
+/// * $8000-$8003: 16 KB PRG bank at $8000
+/// * $9000-$B002: Expansion audio (see `audio::Audio`)
+/// * $B003: PPU banking control (R---MM--: PRG-RAM enable, mirroring)
+/// * $C000-$C003: 8 KB PRG bank at $C000
+/// * $D000-$E003: CHR banks 0-7, 1 KB each
+/// * $F000: IRQ latch
+/// * $F001: IRQ control
+/// * $F002: IRQ acknowledge
+///
+/// The last 8 KB PRG bank is fixed at $E000. Only the 1 KB CHR banking mode
+/// used by the released games is supported.
+///
+/// VRC6a (mapper 24) connects A0 and A1 to the register select pins, VRC6b
+/// (mapper 26) swaps them.
+pub struct Vrc6 {
+    rom: Rom,
+    a0: u16,
+    a1: u16,
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    irq: VrcIrq,
+    audio: Audio,
+}
+
+impl Vrc6 {
+    pub fn new(rom: Rom) -> Vrc6 {
+        let (a0, a1) = if rom.header.mapper() == 26 {
+            (0x02, 0x01)
+        } else {
+            (0x01, 0x02)
+        };
+        let mirroring = rom.header.mirroring();
+        Vrc6 {
+            rom: rom,
+            a0: a0,
+            a1: a1,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            mirroring: mirroring,
+            prg_ram_enabled: false,
+            irq: VrcIrq::default(),
+            audio: Audio::default(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = self.rom.prg.len() / PRG_BANK_SIZE;
+        let bank = match addr {
+            0x8000...0xBFFF => {
+                (self.prg_bank_16k as usize * 2) | ((addr as usize - 0x8000) / PRG_BANK_SIZE)
+            }
+            0xC000...0xDFFF => self.prg_bank_8k as usize,
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.rom.chr.len()
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match vrc_register(addr, self.a0, self.a1) {
+            0x8000...0x8003 => self.prg_bank_16k = val & 0x0F,
+            0xB003 => {
+                self.prg_ram_enabled = val & 0x80 != 0;
+                self.mirroring = match (val >> 2) & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                };
+            }
+            reg @ 0x9000...0xB002 => self.audio.write_register(reg, val),
+            0xC000...0xC003 => self.prg_bank_8k = val & 0x1F,
+            reg @ 0xD000...0xE003 => {
+                let bank = ((reg - 0xD000) >> 12) as usize * 4 + (reg as usize & 0x03);
+                self.chr_banks[bank] = val;
+            }
+            0xF000 => self.irq.write_latch(val),
+            0xF001 => self.irq.write_control(val),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled => {
+                self.rom.sram[(addr as usize - 0x6000) % self.rom.sram.len()]
+            }
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled => {
+                let len = self.rom.sram.len();
+                self.rom.sram[(addr as usize - 0x6000) % len] = val;
+            }
+            0x8000...0xFFFF => self.write_register(addr, val),
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.irq.cpu_cycle();
+        self.audio.step();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_u8(self.prg_bank_16k);
+        state.write_u8(self.prg_bank_8k);
+        state.write_bytes(&self.chr_banks);
+        self.mirroring.save_state(state);
+        state.write_bool(self.prg_ram_enabled);
+        self.irq.save_state(state);
+        self.audio.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.prg_bank_16k = state.read_u8()?;
+        self.prg_bank_8k = state.read_u8()?;
+        state.read_bytes(&mut self.chr_banks)?;
+        self.mirroring = Mirroring::load_state(state)?;
+        self.prg_ram_enabled = state.read_bool()?;
+        self.irq.load_state(state)?;
+        self.audio.load_state(state)
+    }
+}
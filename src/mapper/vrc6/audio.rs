@@ -0,0 +1,226 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Output level of one step of the VRC6 channels, relative to the APU's
+/// output. A pulse at full volume is about as loud as an APU pulse.
+const LEVEL_SCALE: f32 = 0.01;
+
+/// One of the VRC6's two pulse channels.
+#[derive(Default)]
+struct Pulse {
+    volume: u8,
+    /// Duty cycle: the channel is high for `duty + 1` of 16 steps
+    duty: u8,
+    /// Ignore the duty cycle and output the volume constantly
+    constant: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Pulse {
+    fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            // MDDDVVVV: constant mode, duty, volume
+            0 => {
+                self.constant = val & 0x80 != 0;
+                self.duty = (val >> 4) & 0x07;
+                self.volume = val & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | u16::from(val),
+            // E---PPPP: enable, period high
+            _ => {
+                self.period = (self.period & 0x00FF) | u16::from(val & 0x0F) << 8;
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = (self.step + 1) % 16;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.volume);
+        state.write_u8(self.duty);
+        state.write_bool(self.constant);
+        state.write_bool(self.enabled);
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+        state.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.volume = state.read_u8()?;
+        self.duty = state.read_u8()?;
+        self.constant = state.read_bool()?;
+        self.enabled = state.read_bool()?;
+        self.period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.step = state.read_u8()?;
+        Ok(())
+    }
+}
+
+/// The VRC6's sawtooth channel: an accumulator adds the rate every other
+/// timer clock and is reset after seven additions.
+#[derive(Default)]
+struct Sawtooth {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    /// Timer clocks since the accumulator was reset (0-13)
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.rate = val & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | u16::from(val),
+            _ => {
+                self.period = (self.period & 0x00FF) | u16::from(val & 0x0F) << 8;
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step.is_multiple_of(2) {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// The top 5 bits of the accumulator
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.rate);
+        state.write_bool(self.enabled);
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+        state.write_u8(self.step);
+        state.write_u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rate = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        self.period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.step = state.read_u8()?;
+        self.accumulator = state.read_u8()?;
+        Ok(())
+    }
+}
+
+/// The VRC6's expansion audio: two pulse channels with 16-step duty cycles
+/// and a sawtooth channel, all clocked by the CPU clock.
+///
+/// * $9000-$9002: Pulse 1 (control, period low, enable and period high)
+/// * $9003: Frequency control (-----SSH: period shift by 8, by 4, halt)
+/// * $A000-$A002: Pulse 2
+/// * $B000-$B002: Sawtooth (rate, period low, enable and period high)
+#[derive(Default)]
+pub struct Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    sawtooth: Sawtooth,
+    halt: bool,
+    /// Right shift of all periods
+    shift: u8,
+}
+
+impl Audio {
+    /// Handles a write to one of the audio registers, with the address
+    /// normalized to $x000-$x003.
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x9003 => {
+                self.halt = val & 0x01 != 0;
+                self.shift = if val & 0x04 != 0 {
+                    8
+                } else if val & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000...0x9002 => self.pulse1.write_register(addr - 0x9000, val),
+            0xA000...0xA002 => self.pulse2.write_register(addr - 0xA000, val),
+            0xB000...0xB002 => self.sawtooth.write_register(addr - 0xB000, val),
+            _ => {}
+        }
+    }
+
+    /// Advances the channels by one CPU cycle.
+    pub fn step(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.sawtooth.clock(self.shift);
+    }
+
+    pub fn output(&self) -> f32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        f32::from(level) * LEVEL_SCALE
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.sawtooth.save_state(state);
+        state.write_bool(self.halt);
+        state.write_u8(self.shift);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.sawtooth.load_state(state)?;
+        self.halt = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        Ok(())
+    }
+}
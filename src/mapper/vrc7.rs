@@ -0,0 +1,188 @@
+mod audio;
+
+use self::audio::Audio;
+use super::vrc_irq::VrcIrq;
+use super::{Mapper, Mirroring};
+use crate::rom::Rom;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// Konami VRC7 (mapper 85), with FM synthesis.
+///
+/// * $8000, $8010: PRG banks at $8000 and $A000
+/// * $9000: PRG bank at $C000
+/// * $9010, $9030: FM register select and data (see `audio::Audio`)
+/// * $A000-$D010: CHR banks 0-7, 1 KB each
+/// * $E000: Control (RS----MM: PRG-RAM enable, sound reset, mirroring)
+/// * $E010: IRQ latch
+/// * $F000: IRQ control
+/// * $F010: IRQ acknowledge
+///
+/// The last 8 KB PRG bank is fixed at $E000. VRC7a (submapper 2, Lagrange
+/// Point) selects the second register of each pair with A4, VRC7b
+/// (submapper 1) with A3. Without a submapper, both lines are used.
+pub struct Vrc7 {
+    rom: Rom,
+    /// Address lines selecting the second register of each pair
+    select: u16,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    /// Held in reset by bit 6 of $E000
+    audio_reset: bool,
+    irq: VrcIrq,
+    audio: Audio,
+}
+
+impl Vrc7 {
+    pub fn new(rom: Rom) -> Vrc7 {
+        let select = match rom.header.submapper() {
+            1 => 0x08,
+            2 => 0x10,
+            _ => 0x18,
+        };
+        Vrc7 {
+            rom: rom,
+            select: select,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            prg_ram_enabled: false,
+            audio_reset: false,
+            irq: VrcIrq::default(),
+            audio: Audio::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank_count = self.rom.prg.len() / PRG_BANK_SIZE;
+        let bank = match addr {
+            0x8000...0xDFFF => self.prg_banks[(addr as usize - 0x8000) / PRG_BANK_SIZE] as usize,
+            _ => bank_count - 1,
+        };
+        (bank % bank_count) * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        (bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % self.rom.chr.len()
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        // The sound chip decodes A5 and A4 on both boards
+        match addr & 0xF030 {
+            0x9010 => return self.audio.select_register(val),
+            0x9030 => return self.audio.write_data(val),
+            _ => {}
+        }
+        let reg = (addr & 0xF000) | if addr & self.select != 0 { 0x10 } else { 0 };
+        match reg {
+            0x8000 => self.prg_banks[0] = val & 0x3F,
+            0x8010 => self.prg_banks[1] = val & 0x3F,
+            0x9000 => self.prg_banks[2] = val & 0x3F,
+            0xA000...0xD010 => {
+                let bank = ((reg - 0xA000) >> 12) as usize * 2 + (reg as usize & 0x10) / 0x10;
+                self.chr_banks[bank] = val;
+            }
+            0xE000 => {
+                self.mirroring = match val & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                };
+                self.audio_reset = val & 0x40 != 0;
+                if self.audio_reset {
+                    self.audio.reset();
+                }
+                self.prg_ram_enabled = val & 0x80 != 0;
+            }
+            0xE010 => self.irq.write_latch(val),
+            0xF000 => self.irq.write_control(val),
+            0xF010 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled => {
+                self.rom.sram[(addr as usize - 0x6000) % self.rom.sram.len()]
+            }
+            0x8000...0xFFFF => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF if self.prg_ram_enabled => {
+                let len = self.rom.sram.len();
+                self.rom.sram[(addr as usize - 0x6000) % len] = val;
+            }
+            0x8000...0xFFFF => self.write_register(addr, val),
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.rom.header.chr_ram() {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.irq.cpu_cycle();
+        if !self.audio_reset {
+            self.audio.step();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn sram(&mut self) -> &mut [u8] {
+        self.rom.nvram()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        state.write_bytes(&self.prg_banks);
+        state.write_bytes(&self.chr_banks);
+        self.mirroring.save_state(state);
+        state.write_bool(self.prg_ram_enabled);
+        state.write_bool(self.audio_reset);
+        self.irq.save_state(state);
+        self.audio.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        state.read_bytes(&mut self.prg_banks)?;
+        state.read_bytes(&mut self.chr_banks)?;
+        self.mirroring = Mirroring::load_state(state)?;
+        self.prg_ram_enabled = state.read_bool()?;
+        self.audio_reset = state.read_bool()?;
+        self.irq.load_state(state)?;
+        self.audio.load_state(state)
+    }
+}
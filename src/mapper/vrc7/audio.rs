@@ -0,0 +1,491 @@
+use std::f32::consts::PI;
+
+use crate::apu::CPU_CLOCK_RATE;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// The chip computes one sample of every channel each 36 CPU cycles
+const CPU_CYCLES_PER_SAMPLE: u8 = 36;
+const CHANNELS: usize = 6;
+
+/// Built-in instruments 1-15, in the format of the custom instrument
+/// registers $00-$07
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+/// Frequency multipliers selected by the MULT field
+const MULTIPLIERS: [f32; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+/// Key scale attenuation in dB at octave 7, by the top 4 bits of the
+/// F-number. It decreases by 6 dB per octave below.
+const KEY_SCALE_LEVELS: [f32; 16] = [
+    0.0, 9.0, 12.0, 13.875, 15.0, 16.125, 16.875, 17.625, 18.0, 18.75, 19.125, 19.5, 19.875, 20.25,
+    20.625, 21.0,
+];
+
+/// Envelope attenuation step, in dB
+const ENVELOPE_STEP_DB: f32 = 0.375;
+/// Envelope level at which an operator is silent
+const ENVELOPE_MAX: f32 = 127.0;
+/// Attenuation above which an operator's output is negligible, in dB
+const SILENT_DB: f32 = 90.0;
+
+const TREMOLO_RATE: f32 = 3.7;
+const TREMOLO_DEPTH_DB: f32 = 4.8;
+const VIBRATO_RATE: f32 = 6.4;
+/// Relative frequency deviation of the vibrato (about 7 cents)
+const VIBRATO_DEPTH: f32 = 0.004;
+
+/// Output level of a channel at full volume, relative to the APU's output
+const CHANNEL_SCALE: f32 = 0.1;
+
+#[derive(Copy, Clone, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// The parameters of one operator, decoded from an instrument
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    /// Sustained envelope: hold the sustain level while the key is on,
+    /// instead of decaying with the release rate
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: u8,
+    rectify: bool,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+}
+
+impl OperatorPatch {
+    /// Decodes the modulator (0) or carrier (1) of an instrument.
+    fn new(patch: &[u8; 8], op: usize) -> OperatorPatch {
+        OperatorPatch {
+            tremolo: patch[op] & 0x80 != 0,
+            vibrato: patch[op] & 0x40 != 0,
+            sustained: patch[op] & 0x20 != 0,
+            key_scale_rate: patch[op] & 0x10 != 0,
+            multiplier: MULTIPLIERS[(patch[op] & 0x0F) as usize],
+            key_scale_level: patch[2 + op] >> 6,
+            rectify: patch[3] & (0x08 << op) != 0,
+            attack_rate: patch[4 + op] >> 4,
+            decay_rate: patch[4 + op] & 0x0F,
+            sustain_level: patch[6 + op] >> 4,
+            release_rate: patch[6 + op] & 0x0F,
+        }
+    }
+}
+
+/// One of the two operators of a channel: a sine oscillator with an
+/// envelope generator.
+struct Operator {
+    /// Phase, in cycles (0.0-1.0)
+    phase: f32,
+    /// Envelope attenuation, in 0.375 dB steps
+    level: f32,
+    state: EnvelopeState,
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0.0,
+            level: ENVELOPE_MAX,
+            state: EnvelopeState::Release,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        self.state = EnvelopeState::Release;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_f32(self.phase);
+        state.write_f32(self.level);
+        state.write_u8(self.state as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.phase = state.read_f32()?;
+        self.level = state.read_f32()?;
+        self.state = match state.read_u8()? {
+            0 => EnvelopeState::Attack,
+            1 => EnvelopeState::Decay,
+            2 => EnvelopeState::Sustain,
+            3 => EnvelopeState::Release,
+            _ => return Err(StateError::Mismatch),
+        };
+        Ok(())
+    }
+
+    /// Advances the phase by one sample, `frequency` being the channel's
+    /// frequency in cycles per sample.
+    fn clock_phase(&mut self, patch: &OperatorPatch, frequency: f32, vibrato: f32) {
+        let frequency = if patch.vibrato {
+            frequency * vibrato
+        } else {
+            frequency
+        };
+        self.phase = (self.phase + frequency * patch.multiplier) % 1.0;
+    }
+
+    /// Advances the envelope by one sample. `key_scale` is the channel's
+    /// rate key scale (octave and F-number MSB).
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, sustain: bool) {
+        let key_scale = if patch.key_scale_rate {
+            key_scale
+        } else {
+            key_scale >> 2
+        };
+        let rate = |rate: u8| {
+            if rate == 0 {
+                0
+            } else {
+                (rate * 4 + key_scale).min(63)
+            }
+        };
+        match self.state {
+            EnvelopeState::Attack => {
+                let rate = rate(patch.attack_rate);
+                if rate >= 60 {
+                    self.level = 0.0;
+                } else {
+                    // The attack is exponential
+                    self.level -= self.level * envelope_increment(rate) / 8.0;
+                }
+                if self.level < 0.5 {
+                    self.level = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                self.level += envelope_increment(rate(patch.decay_rate));
+                let sustain_level = f32::from(patch.sustain_level) * 8.0;
+                if self.level >= sustain_level {
+                    self.level = sustain_level;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain => {
+                if !patch.sustained {
+                    self.level += envelope_increment(rate(patch.release_rate));
+                }
+            }
+            EnvelopeState::Release => {
+                let release_rate = if sustain { 5 } else { patch.release_rate };
+                self.level += envelope_increment(rate(release_rate));
+            }
+        }
+        self.level = self.level.min(ENVELOPE_MAX);
+    }
+
+    /// The operator's output (-1.0-1.0), with its phase shifted by
+    /// `modulation` cycles and attenuated by `attenuation` dB on top of the
+    /// envelope.
+    fn output(&self, patch: &OperatorPatch, modulation: f32, attenuation: f32) -> f32 {
+        let attenuation = attenuation + self.level * ENVELOPE_STEP_DB;
+        if attenuation >= SILENT_DB || self.level >= ENVELOPE_MAX {
+            return 0.0;
+        }
+        let sine = (2.0 * PI * (self.phase + modulation)).sin();
+        if patch.rectify && sine < 0.0 {
+            0.0
+        } else {
+            sine * 10f32.powf(-attenuation / 20.0)
+        }
+    }
+}
+
+/// Envelope change per sample at an effective rate (0-63), in 0.375 dB
+/// steps. It doubles every 4 rates.
+fn envelope_increment(rate: u8) -> f32 {
+    if rate == 0 {
+        return 0.0;
+    }
+    f32::from(4 + (rate & 3)) * (1u32 << (rate >> 2)) as f32 / 32768.0
+}
+
+struct Channel {
+    /// 9-bit F-number
+    fnum: u16,
+    octave: u8,
+    key_on: bool,
+    /// Sustain flag: slows down the release
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    /// Last two outputs of the modulator, for the feedback
+    feedback: [f32; 2],
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            fnum: 0,
+            octave: 0,
+            key_on: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0; 2],
+        }
+    }
+
+    /// Key scale attenuation of an operator, in dB
+    fn key_scale_attenuation(&self, patch: &OperatorPatch) -> f32 {
+        if patch.key_scale_level == 0 {
+            return 0.0;
+        }
+        let level = KEY_SCALE_LEVELS[(self.fnum >> 5) as usize] - 6.0 * f32::from(7 - self.octave);
+        // The table is for 6 dB/octave; the other settings are 1.5 and 3
+        level.max(0.0) / f32::from(1u8 << (3 - patch.key_scale_level))
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.fnum);
+        state.write_u8(self.octave);
+        state.write_bool(self.key_on);
+        state.write_bool(self.sustain);
+        state.write_u8(self.instrument);
+        state.write_u8(self.volume);
+        self.modulator.save_state(state);
+        self.carrier.save_state(state);
+        state.write_f32(self.feedback[0]);
+        state.write_f32(self.feedback[1]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.fnum = state.read_u16()?;
+        self.octave = state.read_u8()?;
+        self.key_on = state.read_bool()?;
+        self.sustain = state.read_bool()?;
+        self.instrument = state.read_u8()?;
+        self.volume = state.read_u8()?;
+        self.modulator.load_state(state)?;
+        self.carrier.load_state(state)?;
+        self.feedback[0] = state.read_f32()?;
+        self.feedback[1] = state.read_f32()?;
+        Ok(())
+    }
+}
+
+/// The VRC7's FM synthesizer, a cut-down Yamaha YM2413 (OPLL) with six
+/// channels of two operators and no rhythm mode.
+///
+/// Each channel has a modulator, whose output shifts the phase of the
+/// carrier, and plays one of 15 built-in instruments or the custom one
+/// defined by registers $00-$07. The registers are written by selecting one
+/// through $9010 and writing its value to $9030:
+///
+/// * $00-$07: Custom instrument
+/// * $10-$15: F-number low 8 bits
+/// * $20-$25: --SKOOOF: sustain, key on, octave, F-number high bit
+/// * $30-$35: IIIIVVVV: instrument, volume (attenuation)
+pub struct Audio {
+    register: u8,
+    custom_patch: [u8; 8],
+    channels: Vec<Channel>,
+    divider: u8,
+    /// Phases of the tremolo and vibrato, in cycles (0.0-1.0)
+    tremolo_phase: f32,
+    vibrato_phase: f32,
+    output: f32,
+}
+
+impl Audio {
+    pub fn new() -> Audio {
+        Audio {
+            register: 0,
+            custom_patch: [0; 8],
+            channels: (0..CHANNELS).map(|_| Channel::new()).collect(),
+            divider: 0,
+            tremolo_phase: 0.0,
+            vibrato_phase: 0.0,
+            output: 0.0,
+        }
+    }
+
+    pub fn select_register(&mut self, val: u8) {
+        self.register = val;
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        let reg = self.register;
+        let channel = (reg & 0x0F) as usize;
+        match reg {
+            0x00...0x07 => self.custom_patch[reg as usize] = val,
+            0x10...0x15 => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0x100) | u16::from(val);
+            }
+            0x20...0x25 => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0xFF) | u16::from(val & 0x01) << 8;
+                channel.octave = (val >> 1) & 0x07;
+                channel.sustain = val & 0x20 != 0;
+                let key_on = val & 0x10 != 0;
+                if key_on && !channel.key_on {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                } else if !key_on && channel.key_on {
+                    channel.modulator.key_off();
+                    channel.carrier.key_off();
+                }
+                channel.key_on = key_on;
+            }
+            0x30...0x35 => {
+                let channel = &mut self.channels[channel];
+                channel.instrument = val >> 4;
+                channel.volume = val & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    /// Silences all channels, as does bit 6 of $E000.
+    pub fn reset(&mut self) {
+        for channel in self.channels.iter_mut() {
+            *channel = Channel::new();
+        }
+        self.output = 0.0;
+    }
+
+    fn patch(&self, instrument: u8) -> &[u8; 8] {
+        if instrument == 0 {
+            &self.custom_patch
+        } else {
+            &PATCHES[instrument as usize - 1]
+        }
+    }
+
+    /// Advances the chip by one CPU cycle.
+    pub fn step(&mut self) {
+        self.divider += 1;
+        if self.divider < CPU_CYCLES_PER_SAMPLE {
+            return;
+        }
+        self.divider = 0;
+
+        let sample_rate = CPU_CLOCK_RATE as f32 / f32::from(CPU_CYCLES_PER_SAMPLE);
+        self.tremolo_phase = (self.tremolo_phase + TREMOLO_RATE / sample_rate).fract();
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_RATE / sample_rate).fract();
+        let tremolo = TREMOLO_DEPTH_DB * (1.0 - (2.0 * PI * self.tremolo_phase).cos()) / 2.0;
+        let vibrato = 1.0 + VIBRATO_DEPTH * (2.0 * PI * self.vibrato_phase).sin();
+
+        let mut output = 0.0;
+        for i in 0..CHANNELS {
+            let patch = *self.patch(self.channels[i].instrument);
+            let modulator_patch = OperatorPatch::new(&patch, 0);
+            let carrier_patch = OperatorPatch::new(&patch, 1);
+            let channel = &mut self.channels[i];
+
+            // Phases advance by F-number * 2^octave * multiplier / 2^19
+            // cycles per sample
+            let frequency = f32::from(channel.fnum) * f32::from(1u16 << channel.octave) / 524_288.0;
+            channel
+                .modulator
+                .clock_phase(&modulator_patch, frequency, vibrato);
+            channel
+                .carrier
+                .clock_phase(&carrier_patch, frequency, vibrato);
+
+            let key_scale = (channel.octave << 1) | (channel.fnum >> 8) as u8;
+            channel
+                .modulator
+                .clock_envelope(&modulator_patch, key_scale, channel.sustain);
+            channel
+                .carrier
+                .clock_envelope(&carrier_patch, key_scale, channel.sustain);
+
+            // Feedback shifts the modulator's phase by up to 2^FB / 64 cycles
+            let feedback_level = patch[3] & 0x07;
+            let feedback = if feedback_level == 0 {
+                0.0
+            } else {
+                (channel.feedback[0] + channel.feedback[1]) / 2.0 * f32::from(1u8 << feedback_level)
+                    / 64.0
+            };
+            let mut attenuation =
+                f32::from(patch[2] & 0x3F) * 0.75 + channel.key_scale_attenuation(&modulator_patch);
+            if modulator_patch.tremolo {
+                attenuation += tremolo;
+            }
+            let modulation = channel
+                .modulator
+                .output(&modulator_patch, feedback, attenuation);
+            channel.feedback = [channel.feedback[1], modulation];
+
+            // The modulator shifts the carrier's phase by up to 2 cycles
+            let mut attenuation =
+                f32::from(channel.volume) * 3.0 + channel.key_scale_attenuation(&carrier_patch);
+            if carrier_patch.tremolo {
+                attenuation += tremolo;
+            }
+            output += channel
+                .carrier
+                .output(&carrier_patch, modulation * 2.0, attenuation);
+        }
+        self.output = output * CHANNEL_SCALE;
+    }
+
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_bytes(&self.custom_patch);
+        for channel in self.channels.iter() {
+            channel.save_state(state);
+        }
+        state.write_u8(self.divider);
+        state.write_f32(self.tremolo_phase);
+        state.write_f32(self.vibrato_phase);
+        state.write_f32(self.output);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        state.read_bytes(&mut self.custom_patch)?;
+        for channel in self.channels.iter_mut() {
+            channel.load_state(state)?;
+        }
+        self.divider = state.read_u8()?;
+        self.tremolo_phase = state.read_f32()?;
+        self.vibrato_phase = state.read_f32()?;
+        self.output = state.read_f32()?;
+        Ok(())
+    }
+}
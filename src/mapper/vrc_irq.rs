@@ -0,0 +1,176 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// PPU dots per scanline. The prescaler counts them down 3 per CPU cycle.
+const PRESCALER_PERIOD: i16 = 341;
+
+/// The IRQ counter shared by the VRC4, VRC6 and VRC7.
+///
+/// An 8-bit counter counts up from the latched value and raises the IRQ
+/// when it overflows, reloading the latch. In scanline mode, it is clocked
+/// by a prescaler which approximates scanlines from the CPU clock (341 PPU
+/// dots, 3 per CPU cycle); in cycle mode, on every CPU cycle.
+#[derive(Default)]
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    /// Value `enabled` takes when the IRQ is acknowledged
+    enabled_after_ack: bool,
+    cycle_mode: bool,
+    irq: bool,
+}
+
+impl VrcIrq {
+    pub fn write_latch(&mut self, val: u8) {
+        self.latch = val;
+    }
+
+    /// Writes the low nibble of the latch (VRC4).
+    pub fn write_latch_low(&mut self, val: u8) {
+        self.latch = (self.latch & 0xF0) | (val & 0x0F);
+    }
+
+    /// Writes the high nibble of the latch (VRC4).
+    pub fn write_latch_high(&mut self, val: u8) {
+        self.latch = (self.latch & 0x0F) | (val << 4);
+    }
+
+    /// Writes the control register (-----MEA: cycle mode, enable, enable
+    /// after acknowledgement), which also acknowledges the IRQ.
+    pub fn write_control(&mut self, val: u8) {
+        self.enabled_after_ack = val & 0x01 != 0;
+        self.enabled = val & 0x02 != 0;
+        self.cycle_mode = val & 0x04 != 0;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+        self.irq = false;
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.irq = false;
+        self.enabled = self.enabled_after_ack;
+    }
+
+    /// Called once per CPU cycle.
+    pub fn cpu_cycle(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_PERIOD;
+                self.clock();
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.irq = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.latch);
+        state.write_u8(self.counter);
+        state.write_u16(self.prescaler as u16);
+        state.write_bool(self.enabled);
+        state.write_bool(self.enabled_after_ack);
+        state.write_bool(self.cycle_mode);
+        state.write_bool(self.irq);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.latch = state.read_u8()?;
+        self.counter = state.read_u8()?;
+        self.prescaler = state.read_u16()? as i16;
+        self.enabled = state.read_bool()?;
+        self.enabled_after_ack = state.read_bool()?;
+        self.cycle_mode = state.read_bool()?;
+        self.irq = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::VrcIrq;
+
+    /// Returns the number of CPU cycles until the IRQ is raised, up to
+    /// `max`.
+    fn cycles_until_irq(irq: &mut VrcIrq, max: usize) -> Option<usize> {
+        for cycle in 1..=max {
+            irq.cpu_cycle();
+            if irq.irq() {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn cycle_mode() {
+        let mut irq = VrcIrq::default();
+        irq.write_latch(0xFD);
+        irq.write_control(0x06);
+        assert_eq!(cycles_until_irq(&mut irq, 1000), Some(3));
+        // The counter is reloaded from the latch on overflow
+        irq.acknowledge();
+        irq.write_control(0x06);
+        assert_eq!(cycles_until_irq(&mut irq, 1000), Some(3));
+    }
+
+    #[test]
+    fn scanline_mode() {
+        let mut irq = VrcIrq::default();
+        irq.write_latch(0xFE);
+        irq.write_control(0x02);
+        // The prescaler counts down 341 dots, 3 per CPU cycle, so the counter
+        // is clocked every 114 cycles
+        assert_eq!(cycles_until_irq(&mut irq, 1000), Some(2 * 114));
+    }
+
+    #[test]
+    fn write_control_reloads_counter() {
+        let mut irq = VrcIrq::default();
+        irq.write_latch(0xF0);
+        irq.write_control(0x07);
+        assert_eq!(cycles_until_irq(&mut irq, 1000), Some(16));
+        irq.acknowledge();
+        // Writing the latch alone doesn't affect the counter
+        irq.write_latch(0xFE);
+        assert_eq!(cycles_until_irq(&mut irq, 1000), Some(16));
+        irq.write_control(0x07);
+        assert_eq!(cycles_until_irq(&mut irq, 1000), Some(2));
+    }
+
+    #[test]
+    fn acknowledge_copies_enable_after_ack() {
+        let mut irq = VrcIrq::default();
+        irq.write_latch(0xFF);
+        irq.write_control(0x07);
+        assert_eq!(cycles_until_irq(&mut irq, 10), Some(1));
+        irq.acknowledge();
+        assert!(!irq.irq());
+        assert_eq!(cycles_until_irq(&mut irq, 10), Some(1));
+
+        irq.write_control(0x06);
+        assert_eq!(cycles_until_irq(&mut irq, 10), Some(1));
+        irq.acknowledge();
+        assert_eq!(cycles_until_irq(&mut irq, 1000), None);
+    }
+}
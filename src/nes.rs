@@ -0,0 +1,360 @@
+use crate::apu::{self, Apu};
+use crate::bus::NesBus;
+use crate::controller::Buttons;
+use crate::cpu::{Cpu, IrqSource};
+use crate::mapper::{self, Mapper};
+use crate::ppu::Ppu;
+use crate::rom::Rom;
+use crate::wav::WavWriter;
+
+use std::cell::{Ref, RefCell};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Number of PPU dots per CPU cycle on NTSC systems
+const PPU_DOTS_PER_CPU_CYCLE: usize = 3;
+
+/// CPU cycles taken by an OAM DMA transfer: 256 reads and 256 writes plus a
+/// halt cycle. One more alignment cycle is needed when it starts on an odd
+/// cycle.
+const OAM_DMA_CYCLES: usize = 513;
+
+/// CPU cycles stolen by a DMC sample fetch. When it happens during an OAM DMA
+/// it only takes 2.
+const DMC_DMA_CYCLES: usize = 4;
+const DMC_DMA_CYCLES_DURING_OAM_DMA: usize = 2;
+
+/// WAV files the audio output is being recorded to
+struct AudioRecording {
+    mix: WavWriter<BufWriter<File>>,
+    /// One file per channel, if recording channels separately
+    channels: Vec<WavWriter<BufWriter<File>>>,
+    /// The first error writing the files, after which recording stops
+    error: Option<io::Error>,
+}
+
+impl AudioRecording {
+    fn write(&mut self, mix: &[f32], channels: &[Vec<f32>]) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut result = self.mix.write_samples(mix);
+        for (writer, samples) in self.channels.iter_mut().zip(channels) {
+            result = result.and_then(|_| writer.write_samples(samples));
+        }
+        self.error = result.err();
+    }
+
+    fn finish(self) -> io::Result<()> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        self.mix.finish()?;
+        for writer in self.channels {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+fn create_wav(path: &Path, sample_rate: u32) -> io::Result<WavWriter<BufWriter<File>>> {
+    WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+}
+
+/// The whole console: the CPU, the PPU, the APU, the cartridge mapper and the
+/// controllers, wired together.
+///
+/// `Nes` does not depend on a display or any input device, so it can be
+/// driven headlessly (e.g. by tests and tools) as well as by the SDL frontend.
+pub struct Nes {
+    cpu: Cpu<NesBus>,
+    ppu: Rc<RefCell<Ppu>>,
+    apu: Rc<RefCell<Apu>>,
+    mapper: Rc<RefCell<Box<Mapper>>>,
+    /// Whether the cartridge's RAM is battery-backed
+    battery: bool,
+    /// CPU cycles left in the current OAM DMA transfer
+    oam_dma_remaining: usize,
+    /// Audio samples generated by completed frames, until they are taken
+    audio_samples: Vec<f32>,
+    /// Samples of each channel for the current frame, when recording them
+    channel_samples: Vec<Vec<f32>>,
+    recording: Option<AudioRecording>,
+}
+
+impl Nes {
+    /// Builds a console from a ROM image and resets it.
+    pub fn from_rom(rom: Rom) -> Nes {
+        let battery = rom.header.battery();
+        let mapper = Rc::new(RefCell::new(mapper::init(rom)));
+        let ppu = Rc::new(RefCell::new(Ppu::new(mapper.clone())));
+        let apu = Rc::new(RefCell::new(Apu::new()));
+        let cpu = Cpu::new(NesBus::new(mapper.clone(), ppu.clone(), apu.clone()));
+
+        let mut nes = Nes {
+            cpu: cpu,
+            ppu: ppu,
+            apu: apu,
+            mapper: mapper,
+            battery: battery,
+            oam_dma_remaining: 0,
+            audio_samples: Vec::new(),
+            channel_samples: Vec::new(),
+            recording: None,
+        };
+        nes.reset();
+        nes
+    }
+
+    /// Presses the reset button.
+    pub fn reset(&mut self) {
+        self.ppu.borrow_mut().reset();
+        self.apu.borrow_mut().reset();
+        self.cpu.reset();
+    }
+
+    /// Executes a single CPU instruction, lets the PPU and the APU catch up
+    /// with it and returns the number of CPU cycles it took.
+    pub fn step_instruction(&mut self) -> usize {
+        let executing = !self.cpu.stalled();
+        let cycles = self.cpu.step() as usize;
+
+        // Stalled cycles go to the OAM DMA transfer first, DMC fetches
+        // during it only extend it
+        let oam_dma_cycle = !executing && self.oam_dma_remaining > 0;
+        if oam_dma_cycle {
+            self.oam_dma_remaining -= 1;
+        }
+        if self.cpu.bus.take_oam_dma() {
+            let odd = self.cpu.cycles() % 2 == 1;
+            self.oam_dma_remaining = OAM_DMA_CYCLES + odd as usize;
+            self.cpu.stall(self.oam_dma_remaining);
+        }
+
+        // Writes are assumed to happen on the last cycle of the instruction
+        if let Some(val) = self.cpu.bus.take_frame_counter_write() {
+            let odd = (self.cpu.cycles() - 1) % 2 == 1;
+            self.apu
+                .borrow_mut()
+                .write_frame_counter(val, odd, cycles - 1);
+        }
+
+        for cycle in 0..cycles {
+            {
+                let mut ppu = self.ppu.borrow_mut();
+                for _ in 0..PPU_DOTS_PER_CPU_CYCLE {
+                    ppu.step();
+                }
+                self.cpu.set_nmi(ppu.nmi_line(), cycle + 1 == cycles);
+            }
+
+            let expansion_audio = {
+                let mut mapper = self.mapper.borrow_mut();
+                mapper.cpu_cycle();
+                self.cpu.set_irq(IrqSource::Mapper, mapper.irq());
+                mapper.audio_output()
+            };
+
+            let dmc_request = {
+                let mut apu = self.apu.borrow_mut();
+                apu.set_expansion_audio(expansion_audio);
+                apu.step();
+                apu.dmc_request()
+            };
+            if let Some(addr) = dmc_request {
+                self.dmc_dma(addr, executing && cycle + 1 == cycles, oam_dma_cycle);
+            }
+            let apu = self.apu.borrow();
+            self.cpu.set_irq(IrqSource::FrameCounter, apu.frame_irq());
+            self.cpu.set_irq(IrqSource::Dmc, apu.dmc_irq());
+        }
+        cycles
+    }
+
+    /// Fetches a DMC sample byte for the APU and stalls the CPU for it.
+    fn dmc_dma(&mut self, addr: u16, on_read: bool, during_oam_dma: bool) {
+        let val = self.cpu.bus.dmc_dma(addr, on_read && !during_oam_dma);
+        self.apu.borrow_mut().dmc_fill(val);
+        self.cpu.stall(if during_oam_dma {
+            DMC_DMA_CYCLES_DURING_OAM_DMA
+        } else {
+            DMC_DMA_CYCLES
+        });
+    }
+
+    /// Runs the console until the PPU has completed a frame.
+    pub fn step_frame(&mut self) {
+        loop {
+            self.step_instruction();
+            if self.ppu.borrow_mut().take_frame_complete() {
+                break;
+            }
+        }
+
+        let start = self.audio_samples.len();
+        self.apu
+            .borrow_mut()
+            .end_frame(&mut self.audio_samples, &mut self.channel_samples);
+        if let Some(recording) = &mut self.recording {
+            recording.write(&self.audio_samples[start..], &self.channel_samples);
+        }
+        for samples in self.channel_samples.iter_mut() {
+            samples.clear();
+        }
+    }
+
+    /// Starts recording the audio output to a WAV file at `path`, stopping any
+    /// previous recording.
+    ///
+    /// If `channels` is set, each channel is also recorded on its own, to
+    /// files named after `path` and the channel (e.g. `out-pulse1.wav`).
+    pub fn start_recording(&mut self, path: &Path, channels: bool) -> io::Result<()> {
+        self.stop_recording()?;
+
+        let sample_rate = self.apu.borrow().sample_rate().round() as u32;
+        let mut recording = AudioRecording {
+            mix: create_wav(path, sample_rate)?,
+            channels: Vec::new(),
+            error: None,
+        };
+        if channels {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            for name in apu::CHANNEL_NAMES.iter() {
+                let channel_path = path.with_file_name(format!("{}-{}.wav", stem, name));
+                recording
+                    .channels
+                    .push(create_wav(&channel_path, sample_rate)?);
+            }
+        }
+
+        self.apu.borrow_mut().set_channel_outputs(channels);
+        self.channel_samples = vec![Vec::new(); recording.channels.len()];
+        self.recording = Some(recording);
+        Ok(())
+    }
+
+    /// Stops recording audio and finishes the files. Returns the first error
+    /// that occurred while recording, if any.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recording.take() {
+            Some(recording) => {
+                self.apu.borrow_mut().set_channel_outputs(false);
+                self.channel_samples.clear();
+                recording.finish()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the sample rate of the audio output, in Hz.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.apu.borrow_mut().set_sample_rate(sample_rate);
+    }
+
+    /// Takes the audio samples generated since the last call, as mono samples
+    /// between -1.0 and 1.0. They accumulate until taken.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.audio_samples)
+    }
+
+    /// The last rendered picture, as `SCREEN_WIDTH * SCREEN_HEIGHT` RGB24
+    /// pixels.
+    pub fn framebuffer(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.ppu.borrow(), |ppu| &ppu.screen[..])
+    }
+
+    /// Sets the state of the buttons of the controller plugged into `port`
+    /// (0 or 1).
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.cpu.bus.controllers[port].buttons = buttons;
+    }
+
+    /// Whether the cartridge has battery-backed RAM, which should be saved
+    /// across runs.
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// The contents of the battery-backed RAM: the PRG-NVRAM, followed by the
+    /// mapper chip's internal RAM if it has any.
+    pub fn battery_ram(&self) -> Vec<u8> {
+        let mut mapper = self.mapper.borrow_mut();
+        let mut ram = mapper.sram().to_vec();
+        if let Some(internal_ram) = mapper.internal_ram() {
+            ram.extend_from_slice(internal_ram);
+        }
+        ram
+    }
+
+    /// Restores the battery-backed RAM from data returned by `battery_ram`.
+    /// Data of another size (e.g. saved by another emulator) is loaded as far
+    /// as it fits.
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        let mut mapper = self.mapper.borrow_mut();
+        let sram = mapper.sram();
+        let len = sram.len().min(data.len());
+        sram[..len].copy_from_slice(&data[..len]);
+        let data = &data[len..];
+        if let Some(internal_ram) = mapper.internal_ram() {
+            let len = internal_ram.len().min(data.len());
+            internal_ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Nes, DMC_DMA_CYCLES, DMC_DMA_CYCLES_DURING_OAM_DMA};
+    use crate::rom::Rom;
+
+    /// A console running NOPs from $C000
+    fn nes() -> Nes {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x00, 0x00];
+        image.resize(16, 0);
+        image.extend(vec![0xEA; 0x4000]);
+        image[16 + 0x3FFC] = 0x00;
+        image[16 + 0x3FFD] = 0xC0;
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        Nes::from_rom(Rom::load(&mut &image[..]).unwrap())
+    }
+
+    /// Starts a 1-byte DMC sample, which is fetched right away.
+    fn start_sample(nes: &mut Nes) {
+        let mut apu = nes.apu.borrow_mut();
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0x10);
+    }
+
+    /// Steps until the CPU runs again, returning the cycles it was stalled
+    /// for.
+    fn stalled_cycles(nes: &mut Nes) -> usize {
+        let mut cycles = 0;
+        while nes.cpu.stalled() {
+            cycles += nes.step_instruction();
+        }
+        cycles
+    }
+
+    #[test]
+    fn dmc_dma_stalls_cpu() {
+        let mut nes = nes();
+        start_sample(&mut nes);
+        nes.step_instruction();
+        assert_eq!(stalled_cycles(&mut nes), DMC_DMA_CYCLES);
+    }
+
+    #[test]
+    fn dmc_dma_during_oam_dma() {
+        let mut nes = nes();
+        nes.step_instruction();
+        // In the middle of an OAM DMA transfer
+        nes.oam_dma_remaining = 10;
+        nes.cpu.stall(10);
+        start_sample(&mut nes);
+        assert_eq!(stalled_cycles(&mut nes), 10 + DMC_DMA_CYCLES_DURING_OAM_DMA);
+        assert_eq!(nes.oam_dma_remaining, 0);
+    }
+}
@@ -1,7 +1,7 @@
 use bitfield::BitRange;
 use bitfield::bitfield;
 
-use crate::mapper::Mapper;
+use crate::mapper::{Mapper, Mirroring};
 
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -79,6 +79,18 @@ struct Sprite {
     x: u8,
 }
 
+impl Sprite {
+    /// Decodes a sprite from its 4 bytes in OAM.
+    fn from_bytes(bytes: &[u8]) -> Sprite {
+        Sprite {
+            y: bytes[0],
+            tile: bytes[1],
+            attributes: SpriteAttributes(bytes[2]),
+            x: bytes[3],
+        }
+    }
+}
+
 ///
 bitfield!{
     /// The PPUSCTRL register
@@ -183,23 +195,44 @@ pub struct Ppu {
 
     /// Object Attribute Memory which contains a display list of up to 64
     /// sprites, where each sprite's information occupies 4 bytes.
-    primary_oam: [Sprite; 64],
-    /// Secondary OAM contains 
-    secondary_oam: Vec<(Sprite, usize)>,
-
-    nt: [u8; 0x800],
-
-    // The NES uses two palettes, each with 16 entries, the image palette ($3F00-$3F0F) and the
-    // sprite palette ($3F10-$3F1F). Since only 64 unique values are needed,
-    // bits 6 and 7 can be ignored.
-    /// The image palette shows colors available for background tiles.
-    /// It does not store the actual color values, only the index for the color
-    /// in the system palette `PALETTE`.
-    image_palette: [u8; 16],
-    /// The sprite palette shows the colours currently available for sprites.
-    /// It does not store the actual color values, only the index for the color
-    /// in the system palette `PALETTE`.
-    sprite_palette: [u8; 16],
+    primary_oam: [u8; 0x100],
+    /// Secondary OAM holds the (up to 8) sprites found by sprite evaluation
+    /// for the next scanline.
+    secondary_oam: [u8; 32],
+
+    // Sprite evaluation state
+    /// Index of the sprite being evaluated in primary OAM
+    eval_n: usize,
+    /// Index of the byte being read within the sprite
+    eval_m: usize,
+    /// Next free byte in secondary OAM
+    eval_index: usize,
+    /// Set once all 64 sprites have been evaluated
+    eval_done: bool,
+    /// Byte read from primary OAM on the previous (odd) dot
+    oam_buffer: u8,
+    /// Whether sprite 0 was copied to secondary OAM for the next scanline
+    sprite_zero_next: bool,
+
+    // Sprites fetched for the current scanline
+    sprite_count: usize,
+    /// Whether the first fetched sprite is sprite 0
+    sprite_zero_line: bool,
+    sprite_patterns: [u32; 8],
+    sprite_positions: [u8; 8],
+    sprite_priorities: [bool; 8],
+
+    /// Nametable VRAM. Only the first 2 KB are used, except in four-screen
+    /// mode where the cartridge provides the other 2 KB.
+    nt: [u8; 0x1000],
+
+    /// Palette RAM, mirrored through $3F00-$3FFF. It holds the image palette
+    /// ($3F00-$3F0F), which shows the colors available for background tiles,
+    /// and the sprite palette ($3F10-$3F1F).
+    ///
+    /// It does not store the actual color values, only the 6-bit index of the
+    /// color in the system palette `PALETTE`.
+    palette: [u8; 32],
 
     // nametable: [u8; 0x800],
     
@@ -207,6 +240,9 @@ pub struct Ppu {
     // Flags
     /// Even (true) or odd (false)
     even: bool,
+    /// Set when vblank starts, i.e. once the last visible scanline has been
+    /// rendered.
+    frame_complete: bool,
     /// 
     nmi_occured: bool,
     nmi_output: bool,
@@ -228,20 +264,12 @@ pub struct Ppu {
     ppu_status: PpuStatus,
     /// $2003 OAMADDR
     oam_addr: u8,
-    /// $2004 OAMDATA
-    oam_data: u8,
-    /// $2005 PPUSCROLL
-    ppu_scroll: u8,
-    /// $2006 PPUADDR
-    ppu_addr: u8,
-    /// $2007 PPUDATA
-    ppu_data: u8,
-    /// $4014 OAMDMA
-    oam_dma: u8,
-    // Storage
-
-    // palette_data: [u8; 32],
-    // nametable
+    /// Internal buffer returned by $2007 (PPUDATA) reads
+    read_buffer: u8,
+    /// The I/O data bus between the CPU and the PPU, which holds the last
+    /// value written to any register. Reading a write-only register returns
+    /// its content.
+    io_latch: u8,
 }
 
 impl Ppu {
@@ -252,18 +280,23 @@ impl Ppu {
             cycle: 0,
             frame: 0,
             screen: [0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
-            nt: [0; 0x800],
-
-            image_palette: [0; 16],
-            sprite_palette: [0; 16],
-
-            primary_oam: [Sprite {
-                y: 0,
-                tile: 0,
-                x: 0,
-                attributes: SpriteAttributes(0),
-            }; 64],
-            secondary_oam: vec![],
+            nt: [0; 0x1000],
+
+            palette: [0; 32],
+
+            primary_oam: [0; 0x100],
+            secondary_oam: [0xFF; 32],
+            eval_n: 0,
+            eval_m: 0,
+            eval_index: 0,
+            eval_done: false,
+            oam_buffer: 0,
+            sprite_zero_next: false,
+            sprite_count: 0,
+            sprite_zero_line: false,
+            sprite_patterns: [0; 8],
+            sprite_positions: [0; 8],
+            sprite_priorities: [false; 8],
             nametable_byte: 0,
             v: 0,
             t: 0,
@@ -275,6 +308,7 @@ impl Ppu {
             tile_data: 0,
 
             even: true,
+            frame_complete: false,
             nmi_occured: false,
             nmi_output: false,
 
@@ -282,76 +316,234 @@ impl Ppu {
             ppu_mask: PpuMask(0),
             ppu_status: PpuStatus(0),
             oam_addr: 0,
-            oam_data: 0,
-            ppu_scroll: 0,
-            ppu_addr: 0,
-            ppu_data: 0,
-            oam_dma: 0,
+            read_buffer: 0,
+            io_latch: 0,
         }
     }
 
+    /// Reads from the PPU's address space. Accesses below $3F00 go through
+    /// the cartridge, which sees PPU A12; palette RAM is inside the PPU.
     fn read(&self, addr: u16) -> u8 {
+        if addr < 0x3F00 {
+            self.mapper.borrow_mut().ppu_a12(addr & 0x1000 != 0);
+        }
+        match addr {
+            0x0000...0x1FFF => self.mapper.borrow_mut().ppu_read(addr),
+            0x2000...0x3EFF => {
+                let mirroring = self.mapper.borrow().mirroring();
+                match mirroring {
+                    Mirroring::MapperControlled => self.mapper.borrow_mut().ppu_read(addr),
+                    mirroring => self.nt[mirroring.nametable_offset(addr)],
+                }
+            }
+            0x3F00...0x3FFF => self.read_palette(addr),
+            _ => panic!("Invalid read address {:?}", addr)
+        }
+    }
+
+    /// Returns the byte `read` would return, without side effects on the
+    /// cartridge.
+    fn peek(&self, addr: u16) -> u8 {
         match addr {
-            0x0000...0x1FFF => self.mapper.borrow_mut().read(addr),
-            0x2000...0x3EFF => self.nt[addr as usize % 0x800],
-            0x3F00...0x3F0F => self.image_palette[addr as usize],
-            0x3F10...0x3F1F => self.sprite_palette[addr as usize],
-            0x3F20...0x3FFF => self.read(((addr - 0x3F00) % 32) + 0x3F00),
+            0x0000...0x1FFF => self.mapper.borrow().ppu_peek(addr),
+            0x2000...0x3EFF => {
+                let mapper = self.mapper.borrow();
+                match mapper.mirroring() {
+                    Mirroring::MapperControlled => mapper.ppu_peek(addr),
+                    mirroring => self.nt[mirroring.nametable_offset(addr)],
+                }
+            }
+            0x3F00...0x3FFF => self.read_palette(addr),
             _ => panic!("Invalid read address {:?}", addr)
         }
     }
 
+    /// Reads palette RAM ($3F00-$3FFF).
+    fn read_palette(&self, addr: u16) -> u8 {
+        let color = self.palette[Ppu::palette_index(addr)];
+        if self.ppu_mask.grayscale() {
+            color & 0x30
+        } else {
+            color
+        }
+    }
+
     fn write(&mut self, addr: u16, val: u8) {
+        if addr < 0x3F00 {
+            self.mapper.borrow_mut().ppu_a12(addr & 0x1000 != 0);
+        }
         match addr {
-            0x0000...0x1FFF => self.mapper.borrow_mut().write(addr, val),
-            0x2000...0x3EFF => self.nt[addr as usize % 0x800] = val,
-            0x3F00...0x3F0F => self.image_palette[addr as usize] = val,
-            0x3F10...0x3F1F => self.sprite_palette[addr as usize] = val,
-            0x3F20...0x3FFF => self.write(((addr - 0x3F00) % 32) + 0x3F00, val),
+            0x0000...0x1FFF => self.mapper.borrow_mut().ppu_write(addr, val),
+            0x2000...0x3EFF => {
+                let mirroring = self.mapper.borrow().mirroring();
+                match mirroring {
+                    Mirroring::MapperControlled => self.mapper.borrow_mut().ppu_write(addr, val),
+                    mirroring => self.nt[mirroring.nametable_offset(addr)] = val,
+                }
+            }
+            0x3F00...0x3FFF => self.palette[Ppu::palette_index(addr)] = val & 0x3F,
             _ => panic!("Invalid write address {:?}", addr)
         }
     }
 
+    /// Maps a palette address ($3F00-$3FFF) to an index into palette RAM.
+    ///
+    /// Entry 0 of each sprite palette ($3F10/$3F14/$3F18/$3F1C) is shared
+    /// with the corresponding background palette ($3F00/$3F04/$3F08/$3F0C).
+    fn palette_index(addr: u16) -> usize {
+        let index = addr as usize % 32;
+        if index >= 16 && index % 4 == 0 {
+            index - 16
+        } else {
+            index
+        }
+    }
+
+    /// Reads one of the eight PPU registers, which are mirrored every 8 bytes
+    /// through $3FFF.
     pub fn read_register(&mut self, addr: u16) -> u8 {
-        match addr {
+        let val = match 0x2000 + addr % 8 {
             0x2002 => {
-                self.ppu_status.set_vblank_started(self.nmi_occured);
+                let val = self.peek_register(addr);
                 self.nmi_occured = false;
-                self.ppu_status.bit_range(7, 0)
+                self.w = false;
+                val
+            }
+            0x2004 => self.read_oam(self.oam_addr),
+            0x2007 => {
+                let addr = self.v & 0x3FFF;
+                let val = if addr < 0x3F00 {
+                    // Reads return the content of an internal buffer, which
+                    // is then filled with the byte at the VRAM address
+                    let buffered = self.read_buffer;
+                    self.read_buffer = self.read(addr);
+                    buffered
+                } else {
+                    // Palette reads are not buffered, but the buffer is still
+                    // filled with the nametable byte "underneath" the palette
+                    self.read_buffer = self.read(addr - 0x1000);
+                    (self.read(addr) & 0x3F) | (self.io_latch & 0xC0)
+                };
+                self.increment_vram_addr();
+                val
+            }
+            // Write-only registers return the content of the I/O latch
+            _ => self.io_latch,
+        };
+        self.io_latch = val;
+        val
+    }
+
+    /// Returns the value `read_register` would return, without any side
+    /// effects.
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        match 0x2000 + addr % 8 {
+            0x2002 => {
+                let mut status = PpuStatus(self.ppu_status.bit_range(7, 0));
+                status.set_vblank_started(self.nmi_occured);
+                status.set_lsb(self.io_latch & 0x1F);
+                status.bit_range(7, 0)
+            }
+            0x2004 => self.read_oam(self.oam_addr),
+            0x2007 => {
+                let addr = self.v & 0x3FFF;
+                if addr < 0x3F00 {
+                    self.read_buffer
+                } else {
+                    (self.peek(addr) & 0x3F) | (self.io_latch & 0xC0)
+                }
             }
-            0x2004 => self.oam_data.bit_range(7, 0),
-            0x2007 => self.ppu_data.bit_range(7, 0),
-            _ => panic!("{:?} is not a readable register.", addr),
+            _ => self.io_latch,
         }
     }
 
+    /// Writes one of the eight PPU registers, which are mirrored every 8
+    /// bytes through $3FFF.
     pub fn write_register(&mut self, addr: u16, val: u8) {
-        match addr {
+        self.io_latch = val;
+        match 0x2000 + addr % 8 {
             0x2000 => {
+                self.ppu_ctrl.set_bit_range(7, 0, val);
                 self.nmi_output = self.ppu_ctrl.nmi_vblank();
-                self.ppu_ctrl.set_bit_range(7, 0, val)
-            },
+                // t: ...GH.. ........ <- d: ......GH
+                self.t = (self.t & 0xF3FF) | ((u16::from(val) & 0x03) << 10);
+            }
             0x2001 => self.ppu_mask.set_bit_range(7, 0, val),
+            // PPUSTATUS is read-only
+            0x2002 => {}
             0x2003 => self.oam_addr = val,
-            0x2004 => self.oam_data = val,
-            0x2005 => self.ppu_scroll = val,
+            0x2004 => {
+                if self.rendering() && (self.scanline < 240 || self.scanline == 261) {
+                    // Writes during rendering are ignored, but still bump the
+                    // high 6 bits of OAMADDR
+                    self.oam_addr = self.oam_addr.wrapping_add(4);
+                } else {
+                    self.write_oam(val);
+                }
+            }
+            0x2005 => {
+                if self.w {
+                    // t: FGH..AB CDE..... <- d: ABCDEFGH
+                    self.t = (self.t & 0x8C1F)
+                        | ((u16::from(val) & 0x07) << 12)
+                        | ((u16::from(val) & 0xF8) << 2);
+                    self.w = false;
+                } else {
+                    // t: ....... ...ABCDE <- d: ABCDE...
+                    // x:              FGH <- d: .....FGH
+                    self.t = (self.t & 0xFFE0) | (u16::from(val) >> 3);
+                    self.x = val & 0x07;
+                    self.w = true;
+                }
+            }
             0x2006 => {
                 if self.w {
                     self.t = (self.t & 0xFF00) | val as u16;
                     self.v = self.t;
                     self.w = false;
-
+                    // The new address is put on the bus, which mappers
+                    // watching A12 see
+                    self.mapper.borrow_mut().ppu_a12(self.v & 0x1000 != 0);
                 } else {
                     self.t = (self.t & 0x80FF) | ((val as u16 & 0x3F) << 8);
                     self.w = true;
                 }
             }
-            0x2007 => self.ppu_data = val,
-            0x4014 => self.oam_dma = val,
-            _ => panic!("{:?} is not a register!", addr),
+            0x2007 => {
+                self.write(self.v & 0x3FFF, val);
+                self.increment_vram_addr();
+            }
+            _ => unreachable!(),
         }
     }
 
+    /// Whether background or sprite rendering is enabled
+    fn rendering(&self) -> bool {
+        self.ppu_mask.show_background() || self.ppu_mask.show_sprites()
+    }
+
+    /// Increments `v` after a PPUDATA access, by 1 (across) or 32 (down)
+    /// depending on PPUCTRL.
+    fn increment_vram_addr(&mut self) {
+        let increment = if self.ppu_ctrl.vram_addr_incr() { 32 } else { 1 };
+        self.v = (self.v + increment) & 0x7FFF;
+    }
+
+    /// Reads byte `index` of OAM.
+    fn read_oam(&self, index: u8) -> u8 {
+        self.primary_oam[index as usize]
+    }
+
+    /// Writes a byte to OAM at OAMADDR and increments OAMADDR, as done for
+    /// each byte of an OAM DMA transfer.
+    pub fn write_oam(&mut self, val: u8) {
+        // Bits 2-4 of the attribute byte are not implemented in hardware and
+        // read back as 0
+        let val = if self.oam_addr % 4 == 2 { val & 0xE3 } else { val };
+        self.primary_oam[self.oam_addr as usize] = val;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
     pub fn reset(&mut self) {
         self.cycle = 340;
         self.scanline = 240;
@@ -386,82 +578,149 @@ impl Ppu {
         }
     }
 
+    /// Height of sprites in pixels, 8 or 16 depending on PPUCTRL
+    fn sprite_height(&self) -> usize {
+        if self.ppu_ctrl.sprite_size() {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Whether a sprite at `y` is on the current scanline
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let row = self.scanline as isize - y as isize;
+        row >= 0 && row < self.sprite_height() as isize
+    }
+
     /// PPU sprite evaluation is an operation done by the PPU once each
     /// scanline. It prepares the set of sprites and fetches their data to be
     /// rendered on the next scanline. Each scanline, the PPU reads the
     /// Object Attribute Memory to see which to draw:
-    /// * First, it clears the list of sprites to draw.
+    /// * First, it clears the list of sprites to draw (dots 1-64).
     /// * Second, it reads through OAM, checking which sprites will be on this
-    /// scanline. It chooses the first eight it finds that do.
+    /// scanline. It chooses the first eight it finds that do (dots 65-256).
     /// * Third, if eight sprites were found, it checks
     /// (in a wrongly-implemented fashion) for further sprites on the scanline
     /// to see if the sprite overflow flag should be set.
     /// * Fourth, using the details for the eight (or fewer) sprites chosen,
-    /// it determines which pixels each has on the scanline and where to draw them.
+    /// it determines which pixels each has on the scanline and where to draw
+    /// them (dots 257-320, see `fetch_sprite`).
+    ///
+    /// This performs the work of a single dot of the second and third steps:
+    /// odd dots read from primary OAM and even dots write to secondary OAM.
     fn evaluate_sprites(&mut self) {
-        let height = if self.ppu_ctrl.sprite_size() { 8 } else { 16 };
-        self.secondary_oam = vec![];
+        if self.cycle == 65 {
+            self.eval_n = 0;
+            self.eval_m = 0;
+            self.eval_index = 0;
+            self.eval_done = false;
+            self.sprite_zero_next = false;
+        }
 
-        let mut count = 0;
-        for sprite in self.primary_oam.iter() {
-            let row = self.scanline - sprite.y as usize;
-            if row >= height {
-                continue;
-            }
-            if self.secondary_oam.len() < 8 {
-                self.secondary_oam.push((*sprite, row));
-            }
-            count += 1;
+        if self.cycle % 2 == 1 {
+            self.oam_buffer = self.primary_oam[self.eval_n * 4 + self.eval_m];
+            return;
+        }
+        if self.eval_done {
+            return;
         }
-        if count > 8 {
+
+        let val = self.oam_buffer;
+        if self.eval_index < 32 {
+            self.secondary_oam[self.eval_index] = val;
+            if self.eval_m == 0 {
+                if self.sprite_in_range(val) {
+                    if self.eval_n == 0 {
+                        self.sprite_zero_next = true;
+                    }
+                    self.eval_m = 1;
+                    self.eval_index += 1;
+                } else {
+                    self.next_sprite();
+                }
+            } else {
+                self.eval_index += 1;
+                self.eval_m += 1;
+                if self.eval_m == 4 {
+                    self.eval_m = 0;
+                    self.next_sprite();
+                }
+            }
+        } else if self.sprite_in_range(val) {
+            // The ninth sprite on the scanline
             self.ppu_status.set_sprite_overflow(true);
+            self.eval_done = true;
+        } else {
+            // Hardware bug: m is incremented along with n, so the following
+            // sprites' tile, attribute and x bytes are treated as y
+            // coordinates
+            self.eval_m = (self.eval_m + 1) % 4;
+            self.next_sprite();
+        }
+    }
+
+    /// Moves sprite evaluation on to the next sprite in primary OAM.
+    fn next_sprite(&mut self) {
+        self.eval_n += 1;
+        if self.eval_n == 64 {
+            self.eval_n = 0;
+            self.eval_done = true;
         }
     }
 
-    fn sprite_pixel(&self) -> (usize, u32, Option<Sprite>) {
+    /// Fetches the pattern of the sprite in `slot` of secondary OAM for the
+    /// next scanline. Unused slots hold $FF, for which the PPU still fetches
+    /// the pattern of tile $FF.
+    fn fetch_sprite(&mut self, slot: usize) {
+        let sprite = Sprite::from_bytes(&self.secondary_oam[slot * 4..slot * 4 + 4]);
+        let row = self.scanline.wrapping_sub(sprite.y as usize) % self.sprite_height();
+
+        self.sprite_patterns[slot] = self.sprite_pattern(&sprite, row);
+        self.sprite_positions[slot] = sprite.x;
+        self.sprite_priorities[slot] = sprite.attributes.priority();
+    }
+
+    /// Returns the index of the first opaque sprite at the current dot and its
+    /// color (palette and pixel value), or a transparent color.
+    fn sprite_pixel(&self) -> (usize, u32) {
         if !self.ppu_mask.show_sprites() {
-            return (0, 0, None);
+            return (0, 0);
         }
         let x = self.cycle - 1;
         if x < 8 && !self.ppu_mask.show_sprites_left() {
-            return (0, 0, None);
+            return (0, 0);
         }
 
-        for (i, (sprite, row)) in self.secondary_oam.iter().enumerate() {
-            let x = self.cycle - 1;
-            let mut offset = x - sprite.x as usize;
+        for i in 0..self.sprite_count {
+            let offset = x as isize - self.sprite_positions[i] as isize;
             if offset < 0 || offset > 7 {
                 continue;
             }
-            offset = 7 - offset;
-            let color = (self.sprite_pattern(sprite, *row) >> (offset * 4)) & 0x0F;
+            let color = (self.sprite_patterns[i] >> ((7 - offset) * 4)) & 0x0F;
             if color % 4 == 0 {
                 continue;
             }
-            return (i, color, Some(*sprite));
+            return (i, color);
         }
-        (0, 0, None)
+        (0, 0)
     }
 
     fn sprite_pattern(&self, sprite: &Sprite, mut row: usize) -> u32 {
-        let mut tile = sprite.tile;
-
-        row = (if self.ppu_ctrl.sprite_size() { 15 } else { 7 } as usize) - row;
-
-        let addr = if !self.ppu_ctrl.sprite_size() {
-            if sprite.attributes.flip_v() {
-                row = 7 - row;
-            }
+        let height = self.sprite_height();
+        if sprite.attributes.flip_v() {
+            row = height - 1 - row;
+        }
 
+        let addr = if height == 8 {
             let table_addr = 0x1000 * self.ppu_ctrl.sprite_pattern_table_addr() as u16;
 
-            table_addr + 16 * tile as u16 + row as u16
+            table_addr + 16 * sprite.tile as u16 + row as u16
         } else {
-            if sprite.attributes.flip_v() {
-                row = 15 - row;
-            }
-
-            let table_addr = 0x1000 * (tile as u16 & 1);
+            // 8x16 sprites take their pattern table from bit 0 of the tile
+            // number and are made of tiles (tile & $FE) and (tile | 1)
+            let table_addr = 0x1000 * (sprite.tile as u16 & 1);
+            let mut tile = sprite.tile & 0xFE;
             if row > 7 {
                 tile += 1;
                 row -= 8;
@@ -470,16 +729,16 @@ impl Ppu {
             table_addr + 16 * tile as u16 + row as u16
         };
 
-        let palette = sprite.attributes.palette() + 4;
+        let palette = (sprite.attributes.palette() + 4) << 2;
 
         let mut low_tile = self.read(addr);
         let mut high_tile = self.read(addr + 8);
 
         let mut pattern: u32 = 0;
 
-        for _ in 0..7 {
-            let mut a;
-            let mut b;
+        for _ in 0..8 {
+            let a;
+            let b;
 
             if sprite.attributes.flip_h() {
                 a = (low_tile & 1) << 0;
@@ -507,84 +766,108 @@ impl Ppu {
             return 0;
         }
 
-        (self.tile_data >> 32) as u32 >> ((7 - self.ppu_scroll) * 4) & 0x0F
+        (self.tile_data >> 32) as u32 >> ((7 - self.x) * 4) & 0x0F
     }
 
     fn render_pixel(&mut self) {
         let (x, y) = (self.cycle - 1, self.scanline);
 
-        let mut background_color = self.background_pixel();
-        let (i, sprite_color, sprite) = self.sprite_pixel();
+        let background_color = self.background_pixel();
+        let (i, sprite_color) = self.sprite_pixel();
 
         let color = match (background_color % 4 == 0, sprite_color % 4 == 0) {
             (true, true) => 0,
             (true, false) => sprite_color | 0x10,
             (false, true) => background_color,
             (false, false) => {
-                self.ppu_status.set_sprite_zero_hit(i == 0 && x < 255);
+                // Left-clipped and disabled layers are already transparent
+                // here. Sprite 0 hit does not happen at x=255.
+                if i == 0 && self.sprite_zero_line && x != 255 {
+                    self.ppu_status.set_sprite_zero_hit(true);
+                }
 
-                if sprite.unwrap().attributes.priority() {
+                if self.sprite_priorities[i] {
                     background_color
                 } else {
                     sprite_color | 0x10
                 }
             }
         };
-        let addr = (color as u16) % 64;
-        let index = self.read(((addr - 0x3F00) % 32) + 0x3F00) as usize;
-        let r = PALETTE[index as usize * 3 + 2];
-        let g = PALETTE[index as usize * 3 + 1];
-        let b = PALETTE[index as usize * 3 + 0];
+        // Transparent pixels (color 0) show the backdrop color at $3F00
+        let index = self.read_palette(0x3F00 + color as u16) as usize;
+        let r = PALETTE[index * 3];
+        let g = PALETTE[index * 3 + 1];
+        let b = PALETTE[index * 3 + 2];
 
         self.screen[(y * SCREEN_WIDTH + x) * 3 + 0] = r;
         self.screen[(y * SCREEN_WIDTH + x) * 3 + 1] = g;
         self.screen[(y * SCREEN_WIDTH + x) * 3 + 2] = b;
+    }
 
-        println!("{} {} {}", r, g, b);
-
+    /// Level of the PPU's /NMI output: asserted while in vblank with NMI
+    /// generation enabled in PPUCTRL.
+    ///
+    /// Because the CPU only reacts to edges, turning on PPUCTRL bit 7 during
+    /// vblank causes an NMI as well.
+    pub fn nmi_line(&self) -> bool {
+        self.nmi_occured && self.nmi_output
     }
 
+    /// The current scanline and dot
+    #[cfg(test)]
+    pub(crate) fn position(&self) -> (usize, usize) {
+        (self.scanline, self.cycle)
+    }
 
+    /// Returns whether a frame has been completed since the last call.
+    pub fn take_frame_complete(&mut self) -> bool {
+        let complete = self.frame_complete;
+        self.frame_complete = false;
+        complete
+    }
 
     pub fn step(&mut self) {
         // TODO NMI and VBLANK
         self.tick();
+        let rendering = self.rendering();
         let pre_render_line = self.scanline == 261;
         let visible_line = self.scanline < 240;
-
+        let render_line = pre_render_line || visible_line;
 
         let pre_fetch_cycle = 321 <= self.cycle && self.cycle <= 336;
         let visible_cycle = 1 <= self.cycle && self.cycle <= 256;
+        let fetch_cycle = pre_fetch_cycle || visible_cycle;
+        let sprite_fetch_cycle = 257 <= self.cycle && self.cycle <= 320;
 
-        if self.ppu_mask.show_background() {
-            if visible_line && visible_cycle {
-                self.render_pixel()
-            }
+        if visible_line && visible_cycle {
+            self.render_pixel()
+        }
 
-            if (pre_render_line || visible_line) && (pre_fetch_cycle || visible_cycle) {
+        if rendering {
+            if render_line && fetch_cycle {
                 self.tile_data <<= 4;
                 match self.cycle % 8 {
                     1 => {
                         let addr = 0x2000 | (self.v & 0x0FFF);
-                        self.nametable_byte = self.read_register(addr);
+                        self.nametable_byte = self.read(addr);
                     }
                     3 => {
                         let a = self.v;
                         let addr = 0x23C0 | (a & 0x0C00) | ((a >> 4) & 0x38) | ((a >> 2) & 0x07);
                         let shift = ((a >> 4) & 4) | (a & 2);
-                        self.attribute_table_byte = ((self.read_register(addr) >> shift) & 3) << 2;
+                        self.attribute_table_byte = ((self.read(addr) >> shift) & 3) << 2;
                     }
                     5 => {
                         let fine_y = (self.v >> 12) & 7;
                         let table = self.ppu_ctrl.background_pattern_table_addr() as u16;
                         let addr = 0x1000 * table + self.nametable_byte as u16 * 16 + fine_y;
-                        self.low_tile = self.read_register(addr);
+                        self.low_tile = self.read(addr);
                     }
                     7 => {
                         let fine_y = (self.v >> 12) & 7;
                         let table = self.ppu_ctrl.background_pattern_table_addr() as u16;
                         let addr = 0x1000 * table + self.nametable_byte as u16 * 16 + fine_y;
-                        self.high_tile = self.read_register(addr + 8);
+                        self.high_tile = self.read(addr + 8);
                     }
                     0 => {
                         let mut data: usize = 0;
@@ -606,8 +889,8 @@ impl Ppu {
                 self.v = (self.v & 0x841F) | (self.t & 0x7BE0);
             }
 
-            if pre_render_line || visible_line {
-                if pre_fetch_cycle && self.cycle % 8 == 0 {
+            if render_line {
+                if fetch_cycle && self.cycle % 8 == 0 {
                     if self.v & 0x001F == 31 {
                         self.v &= 0xFFE0;
                         self.v ^= 0x0400;
@@ -636,16 +919,49 @@ impl Ppu {
                     self.v = (self.v & 0xFBE0) | (self.t & 0x041F);
                 }
             }
-        }
 
-        if self.ppu_mask.show_sprites() && self.cycle == 257 && visible_line {
-            self.evaluate_sprites();
+            // Sprites
+            if visible_line {
+                match self.cycle {
+                    1...64 if self.cycle % 2 == 0 => {
+                        self.secondary_oam[self.cycle / 2 - 1] = 0xFF;
+                    }
+                    65...256 => self.evaluate_sprites(),
+                    _ => {}
+                }
+            }
+            if render_line && sprite_fetch_cycle {
+                self.oam_addr = 0;
+                if self.cycle == 257 {
+                    // No sprites are evaluated on the pre-render line, so
+                    // none are drawn on the first visible line.
+                    self.sprite_count = if visible_line {
+                        self.eval_index / 4
+                    } else {
+                        0
+                    };
+                    self.sprite_zero_line = visible_line && self.sprite_zero_next;
+                }
+                // Each sprite fetch starts with two garbage nametable reads
+                match (self.cycle - 257) % 8 {
+                    0 | 2 => {
+                        self.read(0x2000 | (self.v & 0x0FFF));
+                    }
+                    4 => self.fetch_sprite((self.cycle - 257) / 8),
+                    _ => {}
+                }
+            }
+            // Unused nametable fetches at the end of the line, which the MMC5
+            // relies on to detect scanlines
+            if render_line && (self.cycle == 337 || self.cycle == 339) {
+                self.read(0x2000 | (self.v & 0x0FFF));
+            }
         }
 
         // vblank
         if self.scanline == 241 && self.cycle == 1 {
             self.nmi_occured = true;
-
+            self.frame_complete = true;
         }
         if pre_render_line && self.cycle == 1 {
             self.nmi_occured = false;
@@ -654,3 +970,67 @@ impl Ppu {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use super::Ppu;
+    use crate::mapper::{Mapper, Mirroring};
+    use crate::state::{StateError, StateReader, StateWriter};
+
+    /// Counts the PPU A12 updates the cartridge gets
+    struct A12Spy {
+        updates: Rc<Cell<usize>>,
+    }
+
+    impl Mapper for A12Spy {
+        fn cpu_write(&mut self, _addr: u16, _val: u8) {}
+
+        fn peek(&self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn ppu_peek(&self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn ppu_write(&mut self, _addr: u16, _val: u8) {}
+
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Vertical
+        }
+
+        fn ppu_a12(&mut self, _high: bool) {
+            self.updates.set(self.updates.get() + 1);
+        }
+
+        fn sram(&mut self) -> &mut [u8] {
+            &mut []
+        }
+
+        fn save_state(&self, _state: &mut StateWriter) {}
+
+        fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn peek_register_does_not_drive_a12() {
+        let updates = Rc::new(Cell::new(0));
+        let spy = A12Spy {
+            updates: updates.clone(),
+        };
+        let mut ppu = Ppu::new(Rc::new(RefCell::new(Box::new(spy))));
+        for &addr in [0x1000u16, 0x2000, 0x3F00].iter() {
+            ppu.write_register(0x2006, (addr >> 8) as u8);
+            ppu.write_register(0x2006, addr as u8);
+            let count = updates.get();
+            ppu.peek_register(0x2007);
+            assert_eq!(updates.get(), count, "peeking ${:04X}", addr);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::mapper::Mirroring;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::util;
 
 use std::fmt;
@@ -23,10 +25,12 @@ pub struct Rom {
     pub header: INesHeader,
     /// PRG-ROM
     pub prg: Vec<u8>,
-    /// CHR-ROM
+    /// CHR-ROM, or 8 KB of CHR-RAM if the cartridge has no CHR-ROM
     pub chr: Vec<u8>,
-    /// SRAM
-    pub sram: [u8; 0x2000],
+    /// SRAM (PRG-RAM)
+    pub sram: Vec<u8>,
+    /// Whether to emulate bus conflicts on boards that have them
+    pub bus_conflicts: bool,
 }
 
 impl Rom {
@@ -41,6 +45,7 @@ impl Rom {
             control_byte_1: header[6],
             control_byte_2: header[7],
             prg_ram_size: header[8],
+            prg_ram_shifts: header[10],
             zero: [0; 7],
         };
 
@@ -55,14 +60,44 @@ impl Rom {
         let chr_bytes = header.chr_rom_size as usize * 8192;
         let mut chr_rom = vec![0u8; chr_bytes];
         r#try!(util::read_to_buf(&mut chr_rom, r));
+        if header.chr_ram() {
+            chr_rom = vec![0u8; 8192];
+        }
+
+        let sram = vec![0u8; header.prg_ram_bytes()];
 
         Ok(Rom {
             header: header,
             prg: prg_rom,
             chr: chr_rom,
-            sram: [0; 0x2000],
+            sram: sram,
+            bus_conflicts: false,
         })
     }
+
+    /// Serializes the cartridge's RAM: PRG-RAM, and CHR-RAM if it has no
+    /// CHR-ROM.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.sram);
+        if self.header.chr_ram() {
+            state.write_bytes(&self.chr);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.sram)?;
+        if self.header.chr_ram() {
+            state.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+
+    /// The battery-backed part of the PRG-RAM, which follows the volatile
+    /// part, or nothing if the cartridge has no battery.
+    pub fn nvram(&mut self) -> &mut [u8] {
+        let start = self.sram.len() - self.header.prg_nvram_bytes();
+        &mut self.sram[start..]
+    }
 }
 
 pub struct INesHeader {
@@ -99,7 +134,13 @@ pub struct INesHeader {
     ///
     /// For compatibility with previous versions of the iNES format, we assume
     /// 1 page of RAM when this is 0.
+    ///
+    /// In NES 2.0 headers, this byte holds the submapper number (high
+    /// nibble) and the high bits of the mapper number instead.
     pub prg_ram_size: u8,
+    /// NES 2.0 only: PRG-NVRAM (high nibble) and PRG-RAM (low nibble) sizes,
+    /// as shift counts: 64 << n bytes, or none if 0.
+    pub prg_ram_shifts: u8,
     /// always zero
     pub zero: [u8; 7],
 }
@@ -110,26 +151,133 @@ impl INesHeader {
         (self.control_byte_2 & 0xf0) | (self.control_byte_1 >> 4)
     }
 
+    /// Whether the header is in NES 2.0 format
+    pub fn nes2(&self) -> bool {
+        self.control_byte_2 & 0x0C == 0x08
+    }
+
+    /// Returns the NES 2.0 submapper number, which tells apart boards that
+    /// share a mapper number, or 0 for iNES headers.
+    pub fn submapper(&self) -> u8 {
+        if self.nes2() {
+            self.prg_ram_size >> 4
+        } else {
+            0
+        }
+    }
+
     /// Returns the low nibble of the mapper ID.
     pub fn ines_mapper(&self) -> u8 {
         self.control_byte_1 >> 4
     }
 
+    /// Whether the cartridge has CHR-RAM instead of CHR-ROM
+    pub fn chr_ram(&self) -> bool {
+        self.chr_rom_size == 0
+    }
+
+    /// Whether the cartridge's PRG-RAM is battery-backed, i.e. holds saves
+    pub fn battery(&self) -> bool {
+        (self.control_byte_1 & 0x02) != 0
+    }
+
+    /// Returns the size of the PRG-RAM in bytes, volatile and battery-backed
+    /// parts together.
+    ///
+    /// Boards without PRG-RAM get 8 KB as well when the header doesn't tell,
+    /// which they just don't map.
+    pub fn prg_ram_bytes(&self) -> usize {
+        if self.nes2() {
+            match shift_bytes(self.prg_ram_shifts & 0x0F) + shift_bytes(self.prg_ram_shifts >> 4) {
+                0 => 8192,
+                bytes => bytes,
+            }
+        } else {
+            self.prg_ram_size.max(1) as usize * 8192
+        }
+    }
+
+    /// Returns the size of the battery-backed PRG-RAM in bytes. NES 2.0
+    /// headers give it separately, iNES ones only tell whether all of the
+    /// PRG-RAM is.
+    pub fn prg_nvram_bytes(&self) -> usize {
+        if !self.battery() {
+            0
+        } else if self.nes2() && self.prg_ram_shifts != 0 {
+            shift_bytes(self.prg_ram_shifts >> 4)
+        } else {
+            self.prg_ram_bytes()
+        }
+    }
+
     pub fn trainer(&self) -> bool {
         (self.control_byte_1 & 0x04) != 0
     }
+
+    /// Returns the nametable mirroring soldered on the board.
+    pub fn mirroring(&self) -> Mirroring {
+        if self.control_byte_1 & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if self.control_byte_1 & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+}
+
+/// Converts a NES 2.0 RAM size shift count to bytes.
+fn shift_bytes(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
 }
 
 impl fmt::Display for INesHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(
             f,
-            "PRG-ROM: {} KB, CHR-ROM: {} KB, Mapper: {} ({}), Trainer: {}",
+            "PRG-ROM: {} KB, CHR-ROM: {} KB, Mapper: {} ({}), Trainer: {}, Battery: {}",
             self.prg_rom_size as u32 * 16,
             self.chr_rom_size as u32 * 8,
             self.mapper(),
             self.ines_mapper(),
             self.trainer(),
+            self.battery(),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::Rom;
+
+    /// A mapper 0 ROM with the given flags 6, flags 7 and RAM size bytes
+    fn rom(flags_6: u8, flags_7: u8, prg_ram_shifts: u8) -> Rom {
+        let mut image = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, flags_6, flags_7];
+        image.resize(16, 0);
+        image[10] = prg_ram_shifts;
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        Rom::load(&mut &image[..]).unwrap()
+    }
+
+    #[test]
+    fn nvram_follows_volatile_ram() {
+        // NES 2.0: 8 KB of PRG-RAM and 8 KB of PRG-NVRAM
+        let mut rom = rom(0x02, 0x08, 0x77);
+        assert_eq!(rom.sram.len(), 0x4000);
+        rom.sram[0x2000] = 0x42;
+        assert_eq!(rom.nvram().len(), 0x2000);
+        assert_eq!(rom.nvram()[0], 0x42);
+    }
+
+    #[test]
+    fn ines_battery_backs_all_ram() {
+        assert_eq!(rom(0x02, 0x00, 0x00).nvram().len(), 0x2000);
+        // Without a battery
+        assert_eq!(rom(0x00, 0x00, 0x00).nvram().len(), 0);
+    }
+}
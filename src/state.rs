@@ -0,0 +1,190 @@
+use std::fmt;
+
+/// An error restoring a saved state
+#[derive(Debug)]
+pub enum StateError {
+    /// The state ended before everything was read
+    Truncated,
+    /// The state doesn't match the emulated hardware (e.g. it was saved with
+    /// another cartridge)
+    Mismatch,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            StateError::Truncated => write!(f, "state is truncated"),
+            StateError::Mismatch => write!(f, "state doesn't match the hardware"),
+        }
+    }
+}
+
+/// Serializes emulator state into a flat little-endian byte buffer.
+///
+/// The format carries no field names or types: a state is read back by the
+/// same sequence of calls on a `StateReader` that wrote it.
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, val: f32) {
+        self.write_u32(val.to_bits());
+    }
+
+    pub fn write_usize(&mut self, val: usize) {
+        self.buf.extend_from_slice(&(val as u64).to_le_bytes());
+    }
+
+    /// Writes a block of bytes, preceded by its length.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_usize(bytes.len());
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads back a state written by a `StateWriter`.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data: data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes) as usize)
+    }
+
+    /// Reads a block of bytes written by `StateWriter::write_bytes` into
+    /// `buf`, which must have the same length.
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), StateError> {
+        if self.read_usize()? != buf.len() {
+            return Err(StateError::Mismatch);
+        }
+        buf.copy_from_slice(self.take(buf.len())?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{StateError, StateReader, StateWriter};
+
+    #[test]
+    fn round_trip() {
+        let mut state = StateWriter::new();
+        state.write_u8(0xAB);
+        state.write_bool(true);
+        state.write_u16(0x1234);
+        state.write_u32(0xDEAD_BEEF);
+        state.write_f32(-0.5);
+        state.write_usize(1 << 40);
+        state.write_bytes(&[1, 2, 3]);
+        let bytes = state.into_bytes();
+
+        let mut state = StateReader::new(&bytes);
+        assert_eq!(state.read_u8().unwrap(), 0xAB);
+        assert!(state.read_bool().unwrap());
+        assert_eq!(state.read_u16().unwrap(), 0x1234);
+        assert_eq!(state.read_u32().unwrap(), 0xDEAD_BEEF);
+        assert_eq!(state.read_f32().unwrap(), -0.5);
+        assert_eq!(state.read_usize().unwrap(), 1 << 40);
+        let mut buf = [0; 3];
+        state.read_bytes(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        assert!(matches!(state.read_u8(), Err(StateError::Truncated)));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut state = StateWriter::new();
+        state.write_u32(1);
+        state.write_bytes(&[1, 2, 3, 4]);
+        let bytes = state.into_bytes();
+
+        assert!(matches!(
+            StateReader::new(&bytes[..3]).read_u32(),
+            Err(StateError::Truncated)
+        ));
+        let mut state = StateReader::new(&bytes[..bytes.len() - 1]);
+        state.read_u32().unwrap();
+        let mut buf = [0; 4];
+        assert!(matches!(
+            state.read_bytes(&mut buf),
+            Err(StateError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let mut state = StateWriter::new();
+        state.write_bytes(&[1, 2, 3, 4]);
+        let bytes = state.into_bytes();
+        let mut buf = [0; 2];
+        assert!(matches!(
+            StateReader::new(&bytes).read_bytes(&mut buf),
+            Err(StateError::Mismatch)
+        ));
+    }
+}